@@ -5,6 +5,7 @@ fn main() {
 fn bindgen_minimp3() {
     let bindings = bindgen::Builder::default()
         .header("../minimp3/minimp3.h")
+        .clang_arg("-DMINIMP3_FLOAT_OUTPUT")
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
         .generate()
         .expect("Unable to generate bindings");
@@ -1,2 +1,3 @@
 #define MINIMP3_IMPLEMENTATION
+#define MINIMP3_FLOAT_OUTPUT
 #include "minimp3.h"
\ No newline at end of file
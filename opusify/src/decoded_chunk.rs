@@ -1,5 +1,62 @@
+use std::sync::{mpsc, Mutex};
+
+/// A block of PCM, as produced by a [`crate::Decoder`] or passed to [`crate::opusify_pcm`].
+///
+/// Blocks can be any length. The sample rate can change between blocks and is resampled
+/// as needed, the channel count should stay the same as the first block's.
 pub struct DecodedChunk {
-    pub pcm: Vec<i16>,
+    /// Interleaved, nominally in -1.0..=1.0
+    pub pcm: Vec<f32>,
+    /// At least 1
     pub channels: usize,
+    /// In Hz
     pub sample_rate: usize,
 }
+
+impl DecodedChunk {
+    fn invalid_reason(&self) -> Option<&'static str> {
+        if self.channels == 0 {
+            Some("chunk has no channels")
+        } else if self.sample_rate == 0 {
+            Some("chunk has a sample rate of 0")
+        } else if !self.pcm.len().is_multiple_of(self.channels) {
+            Some("chunk length isn't a multiple of its channel count")
+        } else {
+            None
+        }
+    }
+}
+
+/// Passes chunks on until one can't be processed, which is reported to `err_tx`.
+/// Stages after this one divide by the channel count and sample rate.
+pub fn check(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+    err_tx: mpsc::Sender<crate::Error>,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        while let Ok(chunk) = in_rx.recv() {
+            if let Some(reason) = chunk.invalid_reason() {
+                let _ = err_tx.send(crate::Error::InvalidPcm { reason });
+                break;
+            }
+            if out_tx.send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+
+    out_rx
+}
+
+/// Ends a stage that reports something besides its output, e.g. [`crate::ConversionStats`].
+/// `shared` is updated before `out_tx` is dropped, so it's complete once the output is.
+pub fn close_with<T>(
+    out_tx: mpsc::Sender<DecodedChunk>,
+    shared: &Mutex<T>,
+    update: impl FnOnce(&mut T),
+) {
+    update(&mut shared.lock().unwrap());
+    drop(out_tx);
+}
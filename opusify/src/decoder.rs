@@ -0,0 +1,103 @@
+use crate::{
+    decoded_chunk::{self, DecodedChunk},
+    mp3, trim,
+};
+use std::{sync::mpsc, time::Duration};
+
+/// Bytes collected from the start of the input before probing.
+const PROBE_BYTES: usize = 64;
+
+/// Decodes an input format, see [`crate::Options::decoders`].
+pub trait Decoder: Send {
+    /// Whether the input looks like this format. `head` is the first bytes of the input,
+    /// shorter only if the whole input is.
+    fn probe(&self, head: &[u8]) -> bool;
+
+    /// Runs on its own thread until `in_rx` is closed, sending chunks with their
+    /// sample rate and channel count. Can return early when `out_tx` is closed.
+    fn decode(
+        &mut self,
+        in_rx: mpsc::Receiver<bytes::Bytes>,
+        out_tx: mpsc::Sender<DecodedChunk>,
+    ) -> anyhow::Result<()>;
+}
+
+/// Probes `decoders` in order, falling back to MP3. Samples before `start` are dropped.
+pub fn decode(
+    in_rx: mpsc::Receiver<bytes::Bytes>,
+    err_tx: mpsc::Sender<crate::Error>,
+    decoders: Vec<Box<dyn Decoder>>,
+    start: Duration,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let mut head = Vec::new();
+        let mut head_len = 0;
+        while head_len < PROBE_BYTES {
+            let Ok(bytes) = in_rx.recv() else {
+                break;
+            };
+            head_len += bytes.len();
+            head.push(bytes);
+        }
+        let head_bytes = head.concat();
+        let head_bytes = &head_bytes[..head_len.min(PROBE_BYTES)];
+
+        let (bytes_tx, bytes_rx) = mpsc::channel();
+        for bytes in head {
+            let _ = bytes_tx.send(bytes);
+        }
+        std::thread::spawn(move || {
+            while let Ok(bytes) = in_rx.recv() {
+                if bytes_tx.send(bytes).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let decoded_rx = match decoders
+            .into_iter()
+            .find(|decoder| decoder.probe(head_bytes))
+        {
+            Some(decoder) => {
+                let decoded_rx = spawn_decoder(decoder, bytes_rx, err_tx.clone());
+                let decoded_rx = decoded_chunk::check(decoded_rx, err_tx);
+                trim::trim(decoded_rx, start, None)
+            }
+            // The MP3 decoder skips to the start by itself
+            None => mp3::decode_mp3(bytes_rx, start),
+        };
+
+        while let Ok(chunk) = decoded_rx.recv() {
+            if out_tx.send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+
+    out_rx
+}
+
+fn spawn_decoder(
+    mut decoder: Box<dyn Decoder>,
+    in_rx: mpsc::Receiver<bytes::Bytes>,
+    err_tx: mpsc::Sender<crate::Error>,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let now = std::time::Instant::now();
+        let result = decoder.decode(in_rx, out_tx.clone());
+
+        println!("decoder thread finished, elapsed: {:?}", now.elapsed());
+
+        // Before dropping out_tx, so the error is there once the output ends
+        if let Err(error) = result {
+            let _ = err_tx.send(crate::Error::Decode { error });
+        }
+        drop(out_tx);
+    });
+
+    out_rx
+}
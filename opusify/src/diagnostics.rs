@@ -0,0 +1,337 @@
+use crate::{
+    decoded_chunk::DecodedChunk,
+    opus::{self, Chunking},
+    quality::{snr_db, ANALYSIS_FRAME_SECONDS},
+    ChannelLayout, EncoderSettings, EncodingMode,
+};
+use anyhow::bail;
+use std::{sync::mpsc, time::Duration};
+
+/// Parallel encoding against serial encoding of the same input, for one chunking.
+#[derive(Debug, Clone)]
+pub struct ChunkingComparison {
+    pub chunking: Chunking,
+    /// Encoding only, for the throughput
+    pub encoding_time: Duration,
+    /// Over the whole input, in dB. Infinite if both outputs are identical.
+    pub snr_db: f64,
+    /// One per job after the first
+    pub boundaries: Vec<BoundaryDifference>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BoundaryDifference {
+    /// Of the job starting here
+    pub sequence_number: usize,
+    /// In the decoded output
+    pub frame: usize,
+    /// Over the 20 ms on either side of `frame`, in dB
+    pub snr_db: f64,
+    /// Largest sample difference in the same range
+    pub max_difference: f32,
+}
+
+/// Encodes `source` serially with one encoder, and in parallel once per chunking, then
+/// decodes everything and measures how far the parallel outputs are from the serial one
+/// around every job boundary. `source` has to be at one of the Opus sample rates.
+pub fn compare_chunking(
+    source: &DecodedChunk,
+    channel_layout: ChannelLayout,
+    encoder_settings: EncoderSettings,
+    chunkings: &[Chunking],
+) -> anyhow::Result<Vec<ChunkingComparison>> {
+    let channels = source.channels;
+    let sample_rate = source.sample_rate;
+    let frames = source.pcm.len() / channels;
+    for &chunking in chunkings {
+        opus::check_chunking(
+            chunking,
+            channels,
+            sample_rate,
+            channel_layout,
+            encoder_settings.frame_duration,
+        )?;
+    }
+
+    let (serial, serial_encoding_time) = encode_and_decode(
+        source,
+        channel_layout,
+        encoder_settings,
+        EncodingMode::Serial,
+        Chunking::from_env(),
+    )?;
+    println!("encoded serially, elapsed: {:?}", serial_encoding_time);
+
+    let window_frames = (ANALYSIS_FRAME_SECONDS * sample_rate as f64) as usize;
+
+    chunkings
+        .iter()
+        .map(|&chunking| {
+            let (parallel, encoding_time) = encode_and_decode(
+                source,
+                channel_layout,
+                encoder_settings,
+                EncodingMode::Parallel,
+                chunking,
+            )?;
+            if parallel.len() != serial.len() {
+                bail!("parallel output is not as long as the serial output");
+            }
+
+            let boundaries = opus::job_boundaries(
+                frames,
+                channels,
+                sample_rate,
+                channel_layout,
+                encoder_settings.frame_duration,
+                chunking,
+            )?
+            .into_iter()
+            .enumerate()
+            .map(|(index, frame)| {
+                let range = frame.saturating_sub(window_frames) * channels
+                    ..((frame + window_frames) * channels).min(serial.len());
+                BoundaryDifference {
+                    sequence_number: index + 1,
+                    frame,
+                    snr_db: snr_db(&serial[range.clone()], &parallel[range.clone()]),
+                    max_difference: serial[range.clone()]
+                        .iter()
+                        .zip(&parallel[range])
+                        .map(|(s, p)| (s - p).abs())
+                        .fold(0.0, f32::max),
+                }
+            })
+            .collect();
+
+            let comparison = ChunkingComparison {
+                chunking,
+                encoding_time,
+                snr_db: snr_db(&serial, &parallel),
+                boundaries,
+            };
+            println!(
+                "compared {:?}, SNR against serial: {:.1} dB, elapsed: {:?}",
+                chunking, comparison.snr_db, encoding_time
+            );
+
+            Ok(comparison)
+        })
+        .collect()
+}
+
+/// `chunking` is only used in parallel.
+fn encode_and_decode(
+    source: &DecodedChunk,
+    channel_layout: ChannelLayout,
+    encoder_settings: EncoderSettings,
+    encoding_mode: EncodingMode,
+    chunking: Chunking,
+) -> anyhow::Result<(Vec<f32>, Duration)> {
+    let now = std::time::Instant::now();
+    let (pcm_tx, pcm_rx) = mpsc::channel();
+    pcm_tx.send(DecodedChunk {
+        pcm: source.pcm.clone(),
+        channels: source.channels,
+        sample_rate: source.sample_rate,
+    })?;
+    drop(pcm_tx);
+
+    let (err_tx, err_rx) = mpsc::channel();
+    let out_rx = opus::encode_to_ogg_opus(
+        pcm_rx,
+        err_tx,
+        channel_layout,
+        Default::default(),
+        encoder_settings,
+        encoding_mode,
+        chunking,
+    )?;
+    let encoded = out_rx.into_iter().flatten().collect::<Vec<u8>>();
+    if let Ok(error) = err_rx.try_recv() {
+        bail!(error);
+    }
+    let encoding_time = now.elapsed();
+
+    Ok((
+        opus::decode_ogg_opus(&encoded, source.sample_rate)?.pcm,
+        encoding_time,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stereo chords changing every half second under a slow swell, with a little noise.
+    /// Deterministic, so the measurements are repeatable.
+    fn music(sample_rate: usize, seconds: usize) -> DecodedChunk {
+        let mut seed = 1u32;
+        let pcm = (0..sample_rate * seconds)
+            .flat_map(|i| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = (seed >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                let time = i as f32 / sample_rate as f32;
+                let note = [220.0, 277.2, 329.6, 440.0][(time * 2.0) as usize % 4];
+                let tone = (1..6)
+                    .map(|harmonic| {
+                        let phase = 2.0 * std::f32::consts::PI * note * harmonic as f32 * time;
+                        phase.sin() / harmonic as f32
+                    })
+                    .sum::<f32>();
+                let left = 0.15 * (1.0 + (time * 3.0).sin()) * tone + 0.05 * noise;
+                [left, 0.8 * left + 0.02 * noise]
+            })
+            .collect();
+        DecodedChunk {
+            pcm,
+            channels: 2,
+            sample_rate,
+        }
+    }
+
+    fn mean_boundary_snr_db(comparison: &ChunkingComparison) -> f64 {
+        comparison
+            .boundaries
+            .iter()
+            .map(|boundary| boundary.snr_db.min(100.0))
+            .sum::<f64>()
+            / comparison.boundaries.len() as f64
+    }
+
+    #[test]
+    fn default_padding_hides_boundaries() {
+        let default = Chunking {
+            padding_frames: 8,
+            middle_frames: 96,
+            quiet_padding_frames: 8,
+        };
+        let unpadded = Chunking {
+            padding_frames: 0,
+            quiet_padding_frames: 0,
+            ..default
+        };
+        let comparisons = compare_chunking(
+            &music(48000, 10),
+            ChannelLayout::Speakers,
+            Default::default(),
+            &[unpadded, default],
+        )
+        .unwrap();
+
+        let unpadded_snr_db = mean_boundary_snr_db(&comparisons[0]);
+        let default_snr_db = mean_boundary_snr_db(&comparisons[1]);
+        assert!(
+            default_snr_db > unpadded_snr_db + 6.0,
+            "{default_snr_db} dB with padding, {unpadded_snr_db} dB without"
+        );
+        // About as close to serial encoding as the rest of the output
+        assert!(
+            default_snr_db > comparisons[1].snr_db - 6.0,
+            "{default_snr_db} dB at the boundaries, {} dB overall",
+            comparisons[1].snr_db
+        );
+    }
+
+    #[test]
+    fn forced_celt_at_boundaries() {
+        let source = music(48000, 10);
+        // libopus switches between modes at this bitrate
+        let bitrate = Some(48000);
+        let chunkings = [Chunking {
+            padding_frames: 8,
+            middle_frames: 96,
+            quiet_padding_frames: 8,
+        }];
+        let compare = |encoder_settings| {
+            let comparisons = compare_chunking(
+                &source,
+                ChannelLayout::Speakers,
+                encoder_settings,
+                &chunkings,
+            )
+            .unwrap();
+            mean_boundary_snr_db(&comparisons[0])
+        };
+
+        let free_snr_db = compare(EncoderSettings {
+            bitrate,
+            ..Default::default()
+        });
+        let forced_snr_db = compare(EncoderSettings {
+            mode: Some(crate::OpusMode::CeltOnly),
+            bandwidth: Some(crate::Bandwidth::Fullband),
+            bitrate,
+            ..Default::default()
+        });
+        assert!(
+            forced_snr_db >= free_snr_db,
+            "{forced_snr_db} dB forced, {free_snr_db} dB left to libopus"
+        );
+    }
+
+    #[test]
+    fn quiet_padding_at_boundaries() {
+        // Silent every other half second, so some jobs start after silence
+        let mut source = music(48000, 10);
+        source
+            .pcm
+            .chunks_mut(2 * 24000)
+            .skip(1)
+            .step_by(2)
+            .for_each(|silence| silence.fill(0.0));
+        let default = Chunking {
+            padding_frames: 8,
+            middle_frames: 96,
+            quiet_padding_frames: 8,
+        };
+        let quiet = Chunking {
+            quiet_padding_frames: 2,
+            ..default
+        };
+
+        let comparisons = compare_chunking(
+            &source,
+            ChannelLayout::Speakers,
+            Default::default(),
+            &[default, quiet],
+        )
+        .unwrap();
+        let default_snr_db = mean_boundary_snr_db(&comparisons[0]);
+        let quiet_snr_db = mean_boundary_snr_db(&comparisons[1]);
+        assert!(
+            quiet_snr_db >= default_snr_db - 0.5,
+            "{quiet_snr_db} dB with quiet padding, {default_snr_db} dB without"
+        );
+    }
+
+    #[test]
+    fn invalid_chunking() {
+        let source = music(48000, 1);
+        for chunking in [
+            Chunking {
+                padding_frames: 8,
+                middle_frames: 0,
+                quiet_padding_frames: 8,
+            },
+            // 2.5 ms frames are shorter than the lookahead
+            Chunking {
+                padding_frames: 0,
+                middle_frames: 1,
+                quiet_padding_frames: 0,
+            },
+        ] {
+            let encoder_settings = EncoderSettings {
+                frame_duration: crate::FrameDuration::Ms2_5,
+                ..Default::default()
+            };
+            let result = compare_chunking(
+                &source,
+                ChannelLayout::Speakers,
+                encoder_settings,
+                &[chunking],
+            );
+            assert!(result.is_err(), "{chunking:?}");
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{decoded_chunk::DecodedChunk, filter::PcmFilter, Fade, FadeCurve};
+use std::collections::VecDeque;
+
+/// Applies the gain and fades. The input has a constant sample rate and channel count,
+/// as it comes after the resampler.
+pub struct Envelope {
+    gain: f32,
+    fade_in: Option<Fade>,
+    fade_out: Option<Fade>,
+    /// Frames seen so far
+    position: usize,
+    /// The last frames of the fade out length, held back until we know where the end is
+    held: VecDeque<f32>,
+    channels: usize,
+    sample_rate: usize,
+}
+
+impl Envelope {
+    pub fn new(gain_db: f32, fade_in: Option<Fade>, fade_out: Option<Fade>) -> Self {
+        Self {
+            gain: 10f32.powf(gain_db / 20.0),
+            fade_in,
+            fade_out,
+            position: 0,
+            held: VecDeque::new(),
+            channels: 0,
+            sample_rate: 0,
+        }
+    }
+}
+
+impl PcmFilter for Envelope {
+    fn process(&mut self, mut chunk: DecodedChunk) -> anyhow::Result<DecodedChunk> {
+        self.channels = chunk.channels;
+        self.sample_rate = chunk.sample_rate;
+
+        let fade_in_frames = self
+            .fade_in
+            .map_or(0, |fade| fade_frames(fade, chunk.sample_rate));
+        let fade_out_frames = self
+            .fade_out
+            .map_or(0, |fade| fade_frames(fade, chunk.sample_rate));
+
+        for frame in chunk.pcm.chunks_exact_mut(chunk.channels) {
+            let mut frame_gain = self.gain;
+            if let Some(fade) = self.fade_in {
+                if self.position < fade_in_frames {
+                    frame_gain *= curve(fade.curve, self.position as f32 / fade_in_frames as f32);
+                }
+            }
+            frame.iter_mut().for_each(|sample| *sample *= frame_gain);
+            self.position += 1;
+        }
+
+        if fade_out_frames != 0 {
+            self.held.extend(chunk.pcm);
+            let release_len = self
+                .held
+                .len()
+                .saturating_sub(fade_out_frames * chunk.channels);
+            chunk.pcm = self.held.drain(..release_len).collect();
+        }
+
+        Ok(chunk)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<Option<DecodedChunk>> {
+        let Some(fade) = self.fade_out else {
+            return Ok(None);
+        };
+        if self.held.is_empty() {
+            return Ok(None);
+        }
+
+        let fade_out_frames = fade_frames(fade, self.sample_rate);
+
+        // Shorter than the fade if the input was, so it still ends at zero
+        let held_frames = self.held.len() / self.channels;
+        let mut pcm: Vec<f32> = std::mem::take(&mut self.held).into();
+        for (index, frame) in pcm.chunks_exact_mut(self.channels).enumerate() {
+            let remaining_frames = held_frames - index - 1;
+            let frame_gain = curve(fade.curve, remaining_frames as f32 / fade_out_frames as f32);
+            frame.iter_mut().for_each(|sample| *sample *= frame_gain);
+        }
+
+        Ok(Some(DecodedChunk {
+            pcm,
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+        }))
+    }
+}
+
+fn fade_frames(fade: Fade, sample_rate: usize) -> usize {
+    (fade.duration.as_secs_f64() * sample_rate as f64).round() as usize
+}
+
+/// Gain for `t` from 0.0 (silent) to 1.0 (full).
+fn curve(fade_curve: FadeCurve, t: f32) -> f32 {
+    match fade_curve {
+        FadeCurve::Linear => t,
+        // -60 dB to 0 dB in equal dB steps, offset so it starts at exactly zero
+        FadeCurve::Logarithmic => (10f32.powf(3.0 * (t - 1.0)) - 0.001) / 0.999,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 100 ms at 48 kHz
+    const FADE_FRAMES: usize = 4800;
+
+    fn fade(curve: FadeCurve) -> Option<Fade> {
+        Some(Fade {
+            duration: Duration::from_millis(100),
+            curve,
+        })
+    }
+
+    /// Stereo at full scale in odd sized pieces, then flushed.
+    fn run(mut envelope: Envelope, frames: usize) -> Vec<f32> {
+        let pcm = vec![1.0; frames * 2];
+        let mut output = Vec::new();
+        for piece in pcm.chunks(777 * 2) {
+            let chunk = DecodedChunk {
+                pcm: piece.to_vec(),
+                channels: 2,
+                sample_rate: 48000,
+            };
+            output.extend(envelope.process(chunk).unwrap().pcm);
+        }
+        if let Some(chunk) = envelope.flush().unwrap() {
+            output.extend(chunk.pcm);
+        }
+        assert_eq!(output.len(), frames * 2);
+        // Both channels get the same gain
+        assert!(output.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+        output.into_iter().step_by(2).collect()
+    }
+
+    #[test]
+    fn gain() {
+        let output = run(Envelope::new(-6.0, None, None), 10000);
+        assert!(output
+            .iter()
+            .all(|&sample| (sample - 0.501187).abs() < 1e-6));
+    }
+
+    #[test]
+    fn fade_in() {
+        for (curve, halfway) in [
+            (FadeCurve::Linear, 0.5),
+            // -30 dB
+            (FadeCurve::Logarithmic, (0.031623 - 0.001) / 0.999),
+        ] {
+            let output = run(Envelope::new(0.0, fade(curve), None), 10000);
+
+            assert_eq!(output[0], 0.0, "{curve:?}");
+            assert!(
+                (output[FADE_FRAMES / 2] - halfway).abs() < 1e-5,
+                "{curve:?}"
+            );
+            assert!(output[..FADE_FRAMES]
+                .windows(2)
+                .all(|pair| pair[0] < pair[1]));
+            assert!(output[FADE_FRAMES..].iter().all(|&sample| sample == 1.0));
+        }
+    }
+
+    #[test]
+    fn fade_out() {
+        for curve in [FadeCurve::Linear, FadeCurve::Logarithmic] {
+            let output = run(Envelope::new(0.0, None, fade(curve)), 10000);
+            let fade_start = output.len() - FADE_FRAMES;
+
+            assert!(output[..fade_start].iter().all(|&sample| sample == 1.0));
+            assert!(output[fade_start - 1..]
+                .windows(2)
+                .all(|pair| pair[0] > pair[1]));
+            assert_eq!(*output.last().unwrap(), 0.0, "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn input_shorter_than_fade_out() {
+        let output = run(Envelope::new(0.0, None, fade(FadeCurve::Linear)), 1000);
+
+        // The fade is cut at its start, not squeezed
+        assert_eq!(output[0], 999.0 / FADE_FRAMES as f32);
+        assert_eq!(*output.last().unwrap(), 0.0);
+    }
+}
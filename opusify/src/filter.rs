@@ -0,0 +1,143 @@
+use crate::decoded_chunk::DecodedChunk;
+use std::sync::mpsc;
+
+/// Custom processing between resampling and encoding, see [`crate::Options::filters`].
+///
+/// Chunks arrive with the sample rate and channel count the encoder will use.
+/// A filter may return fewer samples than it was given, e.g. to hold some back for a
+/// lookahead, as long as it returns them from [`PcmFilter::flush`].
+pub trait PcmFilter: Send {
+    fn process(&mut self, chunk: DecodedChunk) -> anyhow::Result<DecodedChunk>;
+
+    /// Called once at the end of the stream, for anything held back.
+    fn flush(&mut self) -> anyhow::Result<Option<DecodedChunk>> {
+        Ok(None)
+    }
+}
+
+/// Runs every chunk through the filters in order.
+pub fn filter(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+    err_tx: mpsc::Sender<crate::Error>,
+    mut filters: Vec<Box<dyn PcmFilter>>,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let now = std::time::Instant::now();
+        let result: anyhow::Result<()> = (|| {
+            while let Ok(chunk) = in_rx.recv() {
+                send_non_empty(&out_tx, process(&mut filters, chunk)?)?;
+            }
+
+            // Whatever a filter flushes still goes through the filters after it
+            for index in 0..filters.len() {
+                if let Some(chunk) = filters[index].flush()? {
+                    send_non_empty(&out_tx, process(&mut filters[index + 1..], chunk)?)?;
+                }
+            }
+
+            Ok(())
+        })();
+
+        println!("filter thread finished, elapsed: {:?}", now.elapsed());
+
+        if let Err(error) = result {
+            let _ = err_tx.send(crate::Error::Filter { error });
+        }
+    });
+
+    out_rx
+}
+
+fn process(
+    filters: &mut [Box<dyn PcmFilter>],
+    chunk: DecodedChunk,
+) -> anyhow::Result<DecodedChunk> {
+    filters
+        .iter_mut()
+        .try_fold(chunk, |chunk, filter| filter.process(chunk))
+}
+
+fn send_non_empty(out_tx: &mpsc::Sender<DecodedChunk>, chunk: DecodedChunk) -> anyhow::Result<()> {
+    if !chunk.pcm.is_empty() {
+        out_tx.send(chunk)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds `add` to every sample and holds back the last `frames` of mono.
+    struct Delay {
+        frames: usize,
+        add: f32,
+        held: Vec<f32>,
+    }
+
+    impl Delay {
+        fn boxed(frames: usize, add: f32) -> Box<dyn PcmFilter> {
+            Box::new(Self {
+                frames,
+                add,
+                held: Vec::new(),
+            })
+        }
+    }
+
+    impl PcmFilter for Delay {
+        fn process(&mut self, mut chunk: DecodedChunk) -> anyhow::Result<DecodedChunk> {
+            self.held
+                .extend(chunk.pcm.iter().map(|sample| sample + self.add));
+            let release_len = self.held.len().saturating_sub(self.frames);
+            chunk.pcm = self.held.drain(..release_len).collect();
+            Ok(chunk)
+        }
+
+        fn flush(&mut self) -> anyhow::Result<Option<DecodedChunk>> {
+            Ok(Some(DecodedChunk {
+                pcm: std::mem::take(&mut self.held),
+                channels: 1,
+                sample_rate: 48000,
+            }))
+        }
+    }
+
+    #[test]
+    fn held_back_samples_go_through_the_later_filters() {
+        let pcm = (0..10000).map(|i| i as f32).collect::<Vec<_>>();
+
+        let (in_tx, in_rx) = mpsc::channel();
+        // Smaller than what the filters hold back, so most of them come out empty
+        for piece in pcm.chunks(100) {
+            in_tx
+                .send(DecodedChunk {
+                    pcm: piece.to_vec(),
+                    channels: 1,
+                    sample_rate: 48000,
+                })
+                .unwrap();
+        }
+        drop(in_tx);
+
+        let (err_tx, err_rx) = mpsc::channel();
+        let output = filter(
+            in_rx,
+            err_tx,
+            vec![Delay::boxed(300, 1.0), Delay::boxed(500, 2.0)],
+        )
+        .iter()
+        .collect::<Vec<_>>();
+
+        assert!(err_rx.try_recv().is_err());
+        assert!(output.iter().all(|chunk| !chunk.pcm.is_empty()));
+        let output = output
+            .into_iter()
+            .flat_map(|chunk| chunk.pcm)
+            .collect::<Vec<_>>();
+        let expected = pcm.iter().map(|sample| sample + 3.0).collect::<Vec<_>>();
+        assert!(output == expected);
+    }
+}
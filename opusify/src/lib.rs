@@ -1,25 +1,223 @@
 mod decoded_chunk;
+mod decoder;
+mod diagnostics;
+mod envelope;
+mod filter;
 #[allow(non_camel_case_types)]
 #[allow(non_upper_case_globals)]
 #[allow(dead_code)]
 mod minimp3_bindings;
 mod mp3;
+mod options;
 mod opus;
+mod quality;
+mod quantize;
+mod raw;
+mod remix;
 mod resample;
+mod silence;
+mod trim;
 
 use anyhow::bail;
-use std::io::Read;
+pub use decoded_chunk::DecodedChunk;
+pub use decoder::Decoder;
+pub use diagnostics::{BoundaryDifference, ChunkingComparison};
+pub use filter::PcmFilter;
+pub use options::*;
+pub use opus::Chunking;
+pub use quality::{ChunkQuality, QualityReport};
+use std::{
+    io::Read,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
 
 const OUT_SAMPLE_RATE: usize = 48000;
 
 pub fn opusify(path: impl AsRef<std::path::Path>) -> anyhow::Result<Vec<u8>> {
-    let (bytes_tx, bytes_rx) = std::sync::mpsc::channel();
-    let (err_tx, err_rx) = std::sync::mpsc::channel();
+    Ok(opusify_with_options(path, Options::default())?.output)
+}
+
+pub fn opusify_with_options(
+    path: impl AsRef<std::path::Path>,
+    mut options: Options,
+) -> anyhow::Result<Conversion> {
+    let (bytes_tx, bytes_rx) = mpsc::channel();
+    let (err_tx, err_rx) = mpsc::channel();
+    // Carried over from Ogg Opus input
+    let comments = Arc::new(Mutex::new(Vec::new()));
 
-    let out_rx = mp3::decode_mp3(bytes_rx);
-    let out_rx = resample::resample(out_rx, err_tx.clone());
     spawn_file_reader(path, bytes_tx, err_tx.clone())?;
-    let out_rx = opus::encode_to_ogg_opus(out_rx, err_tx.clone())?;
+    let decoders = match options.raw_pcm {
+        Some(format) => vec![Box::new(raw::RawPcmDecoder::new(format)) as Box<dyn Decoder>],
+        None => {
+            let mut decoders = std::mem::take(&mut options.decoders);
+            // Converted channels don't have to match the input's mapping family
+            let channel_layout = match options.channel_conversion {
+                Some(_) => None,
+                None => Some(options.channel_layout),
+            };
+            decoders.push(Box::new(opus::OggOpusDecoder::new(
+                comments.clone(),
+                OUT_SAMPLE_RATE,
+                channel_layout,
+            )));
+            decoders
+        }
+    };
+    let decoded_rx = decoder::decode(bytes_rx, err_tx.clone(), decoders, options.time_range.start);
+
+    // The decoder drops the samples before the start
+    convert(
+        decoded_rx,
+        Duration::ZERO,
+        comments,
+        err_tx,
+        err_rx,
+        options,
+    )
+}
+
+/// Replaces the tags of an Ogg Opus file without re-encoding it.
+pub fn remux(path: impl AsRef<std::path::Path>, tag_edit: &TagEdit) -> anyhow::Result<Vec<u8>> {
+    let input = std::fs::read(path)?;
+    let output = opus::remux(&input, tag_edit)?;
+    println!("remux finished, output size: {}", output.len());
+    Ok(output)
+}
+
+/// Decodes Ogg Opus, e.g. the output of [`opusify`], back to 48 kHz PCM.
+/// The pre-skip, the end trimming and the output gain are applied.
+pub fn decode_opus(input: &[u8]) -> anyhow::Result<DecodedChunk> {
+    opus::decode_ogg_opus(input, OUT_SAMPLE_RATE)
+}
+
+/// Same as [`decode_opus`], written as a 32-bit float WAV file.
+pub fn decode_opus_to_wav(
+    input: &[u8],
+    wav_path: impl AsRef<std::path::Path>,
+) -> anyhow::Result<()> {
+    let decoded = decode_opus(input)?;
+
+    let mut writer = hound::WavWriter::create(
+        wav_path,
+        hound::WavSpec {
+            channels: decoded.channels as u16,
+            sample_rate: decoded.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        },
+    )?;
+    for sample in decoded.pcm {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+
+    Ok(())
+}
+
+/// Encodes PCM that is already decoded, e.g. from a synthesizer.
+/// [`Options::decoders`] is not used.
+pub fn opusify_pcm(
+    pcm: impl Iterator<Item = DecodedChunk> + Send + 'static,
+    options: Options,
+) -> anyhow::Result<Conversion> {
+    let (pcm_tx, pcm_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        for chunk in pcm {
+            if pcm_tx.send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+
+    opusify_pcm_channel(pcm_rx, options)
+}
+
+/// Same as [`opusify_pcm`], for PCM produced on another thread.
+/// The input ends when every sender is dropped.
+pub fn opusify_pcm_channel(
+    pcm_rx: mpsc::Receiver<DecodedChunk>,
+    options: Options,
+) -> anyhow::Result<Conversion> {
+    let (err_tx, err_rx) = mpsc::channel();
+    let start = options.time_range.start;
+    let pcm_rx = decoded_chunk::check(pcm_rx, err_tx.clone());
+
+    convert(pcm_rx, start, Default::default(), err_tx, err_rx, options)
+}
+
+/// Everything after decoding. `skip` is how much of [`Options::time_range`] is still
+/// to be dropped from the start of `decoded_rx`.
+fn convert(
+    decoded_rx: mpsc::Receiver<DecodedChunk>,
+    skip: Duration,
+    comments: Arc<Mutex<Vec<String>>>,
+    err_tx: mpsc::Sender<Error>,
+    err_rx: mpsc::Receiver<Error>,
+    options: Options,
+) -> anyhow::Result<Conversion> {
+    let stats = Arc::new(Mutex::new(ConversionStats::default()));
+
+    let duration = options
+        .time_range
+        .end
+        .map(|end| end.saturating_sub(options.time_range.start));
+    let out_rx = if skip.is_zero() && duration.is_none() {
+        decoded_rx
+    } else {
+        trim::trim(decoded_rx, skip, duration)
+    };
+    let out_rx = match options.silence_trim {
+        Some(silence_trim) => silence::trim_silence(out_rx, silence_trim, stats.clone()),
+        None => out_rx,
+    };
+    let out_rx = resample::resample(out_rx, err_tx.clone(), options.resample_quality);
+    let out_rx = match options.channel_conversion {
+        Some(conversion) => remix::remix(out_rx, err_tx.clone(), conversion),
+        None => out_rx,
+    };
+    let mut filters = options.filters;
+    if options.gain_db != 0.0 || options.fade_in.is_some() || options.fade_out.is_some() {
+        filters.push(Box::new(envelope::Envelope::new(
+            options.gain_db,
+            options.fade_in,
+            options.fade_out,
+        )));
+    }
+    let out_rx = if filters.is_empty() {
+        out_rx
+    } else {
+        filter::filter(out_rx, err_tx.clone(), filters)
+    };
+    let out_rx = match options.quantization {
+        Some(quantization) => quantize::quantize(out_rx, quantization, stats.clone()),
+        None => out_rx,
+    };
+    let (out_rx, source) = if options.quality_check {
+        let (out_rx, source) = quality::record(out_rx);
+        (out_rx, Some(source))
+    } else {
+        (out_rx, None)
+    };
+    let chunking = options.chunking.unwrap_or_else(Chunking::from_env);
+    let out_rx = match opus::encode_to_ogg_opus(
+        out_rx,
+        err_tx.clone(),
+        options.channel_layout,
+        comments,
+        options.encoder_settings,
+        options.encoding_mode,
+        chunking,
+    ) {
+        Ok(out_rx) => out_rx,
+        // A stage before the encoder failed and closed its output
+        Err(error) => match err_rx.try_recv() {
+            Ok(stage_error) => bail!(stage_error),
+            Err(_) => return Err(error),
+        },
+    };
 
     let mut output = Vec::new();
     while let Ok(bytes) = out_rx.recv() {
@@ -31,13 +229,86 @@ pub fn opusify(path: impl AsRef<std::path::Path>) -> anyhow::Result<Vec<u8>> {
         bail!(error);
     }
 
-    Ok(output)
+    let stats = stats.lock().unwrap().clone();
+
+    let quality = match source
+        .as_ref()
+        .and_then(|source| source.lock().unwrap().take())
+    {
+        Some(source) => {
+            let chunking = match options.encoding_mode {
+                EncodingMode::Parallel => Some(chunking),
+                EncodingMode::Serial => None,
+            };
+            let quality = quality::analyze(
+                &source,
+                &output,
+                options.channel_layout,
+                options.encoder_settings.frame_duration,
+                chunking,
+            )?;
+            println!(
+                "quality check finished, SNR: {:.1} dB, spectral distortion: {:.2} dB",
+                quality.snr_db, quality.spectral_distortion_db
+            );
+            Some(quality)
+        }
+        None => None,
+    };
+
+    Ok(Conversion {
+        output,
+        stats,
+        quality,
+    })
+}
+
+/// Compares Ogg Opus output with `source`, the PCM that went into the encoder,
+/// resampled to an Opus sample rate. See [`Options::quality_check`].
+/// `chunking` is `None` if it was encoded with [`EncodingMode::Serial`].
+pub fn analyze_quality(
+    source: &DecodedChunk,
+    encoded: &[u8],
+    channel_layout: ChannelLayout,
+    frame_duration: FrameDuration,
+    chunking: Option<Chunking>,
+) -> anyhow::Result<QualityReport> {
+    quality::analyze(source, encoded, channel_layout, frame_duration, chunking)
+}
+
+/// Encodes `source` serially and with every chunking in parallel, and compares the
+/// decoded outputs around the job boundaries. `source` has to be at an Opus sample rate.
+pub fn compare_chunking(
+    source: &DecodedChunk,
+    channel_layout: ChannelLayout,
+    encoder_settings: EncoderSettings,
+    chunkings: &[Chunking],
+) -> anyhow::Result<Vec<ChunkingComparison>> {
+    diagnostics::compare_chunking(source, channel_layout, encoder_settings, chunkings)
+}
+
+pub struct Conversion {
+    /// Ogg Opus file
+    pub output: Vec<u8>,
+    pub stats: ConversionStats,
+    /// Only with [`Options::quality_check`]
+    pub quality: Option<QualityReport>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConversionStats {
+    /// Samples clamped to the 16-bit range by [`Options::quantization`]
+    pub clipped_samples: usize,
+    /// Removed by [`Options::silence_trim`]
+    pub trimmed_leading_silence: Duration,
+    /// Removed by [`Options::silence_trim`]
+    pub trimmed_trailing_silence: Duration,
 }
 
 fn spawn_file_reader(
     path: impl AsRef<std::path::Path>,
-    bytes_tx: std::sync::mpsc::Sender<bytes::Bytes>,
-    err_tx: std::sync::mpsc::Sender<Error>,
+    bytes_tx: mpsc::Sender<bytes::Bytes>,
+    err_tx: mpsc::Sender<Error>,
 ) -> anyhow::Result<()> {
     let mut file = std::fs::File::open(path)?;
     std::thread::spawn(move || {
@@ -71,12 +342,39 @@ pub enum Error {
     ByteRecv {
         error: anyhow::Error,
     },
+    Decode {
+        error: anyhow::Error,
+    },
     Resample {
         error: rubato::ResamplerConstructionError,
     },
     OpusEncode {
         reason: &'static str,
     },
+    OpusDecode {
+        reason: &'static str,
+    },
+    OggRead {
+        reason: &'static str,
+        /// Of the page, in bytes from the start of the input
+        offset: u64,
+    },
+    ChannelConversion {
+        reason: &'static str,
+    },
+    InvalidChunking {
+        reason: &'static str,
+    },
+    /// A [`DecodedChunk`] with no channels, a sample rate of 0 or a partial frame
+    InvalidPcm {
+        reason: &'static str,
+    },
+    Filter {
+        error: anyhow::Error,
+    },
+    /// Nothing was left to encode, e.g. the input was all silence or
+    /// [`Options::time_range`] starts after its end
+    NoAudio,
 }
 
 impl std::fmt::Display for Error {
@@ -86,3 +384,170 @@ impl std::fmt::Display for Error {
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(channels: usize, options: Options) -> Vec<u8> {
+        let pcm = (0..48000 * channels)
+            .map(|i| 0.3 * (i as f32 / channels as f32 * 0.05).sin())
+            .collect();
+        let chunk = DecodedChunk {
+            pcm,
+            channels,
+            sample_rate: OUT_SAMPLE_RATE,
+        };
+        opusify_pcm(std::iter::once(chunk), options).unwrap().output
+    }
+
+    fn write_temp(name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("opusify-{}-{name}", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn conversion_error(result: anyhow::Result<Conversion>) -> Error {
+        let Err(error) = result else {
+            panic!("conversion succeeded");
+        };
+        error.downcast().unwrap()
+    }
+
+    fn bitrate_options(bitrate: u32) -> Options {
+        Options {
+            encoder_settings: EncoderSettings {
+                bitrate: Some(bitrate),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reencode_at_lower_bitrate() {
+        let input = encode(2, bitrate_options(128000));
+        let path = write_temp("reencode.opus", &input);
+
+        let output = opusify_with_options(&path, bitrate_options(32000))
+            .unwrap()
+            .output;
+        std::fs::remove_file(path).unwrap();
+
+        assert!(output.len() < input.len() / 2);
+        assert_eq!(decode_opus(&output).unwrap().pcm.len(), 48000 * 2);
+    }
+
+    #[test]
+    fn ambisonic_input_keeps_its_layout() {
+        let input = encode(
+            4,
+            Options {
+                channel_layout: ChannelLayout::Ambisonics,
+                ..Default::default()
+            },
+        );
+        let path = write_temp("ambisonics.opus", &input);
+
+        let speakers = opusify_with_options(&path, Options::default());
+        let ambisonics = opusify_with_options(
+            &path,
+            Options {
+                channel_layout: ChannelLayout::AmbisonicsProjection,
+                ..Default::default()
+            },
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let Error::Decode { error } = conversion_error(speakers) else {
+            panic!("not a decoder error");
+        };
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::ChannelConversion { .. })
+        ));
+        assert_eq!(
+            decode_opus(&ambisonics.unwrap().output).unwrap().channels,
+            4
+        );
+    }
+
+    #[test]
+    fn invalid_pcm() {
+        let convert = |channels, sample_rate| {
+            let chunk = DecodedChunk {
+                pcm: vec![0.0; 4800],
+                channels,
+                sample_rate,
+            };
+            opusify_pcm(std::iter::once(chunk), Options::default())
+        };
+
+        let is_invalid = |result| matches!(conversion_error(result), Error::InvalidPcm { .. });
+        assert!(is_invalid(convert(0, OUT_SAMPLE_RATE)));
+        assert!(is_invalid(convert(2, 0)));
+        assert!(is_invalid(convert(7, OUT_SAMPLE_RATE)));
+        assert!(convert(2, OUT_SAMPLE_RATE).is_ok());
+    }
+
+    #[test]
+    fn same_output_every_run() {
+        let options = |encoding_mode| Options {
+            encoding_mode,
+            // Enough jobs for several to be encoded at once
+            chunking: Some(Chunking {
+                padding_frames: 2,
+                middle_frames: 4,
+                quiet_padding_frames: 2,
+            }),
+            ..Default::default()
+        };
+
+        for encoding_mode in [EncodingMode::Parallel, EncodingMode::Serial] {
+            let first = encode(2, options(encoding_mode));
+            // At the same time, so the jobs of one run finish in a different order
+            let runs = (0..3)
+                .map(|_| std::thread::spawn(move || encode(2, options(encoding_mode))))
+                .collect::<Vec<_>>();
+            for run in runs {
+                assert!(run.join().unwrap() == first, "{encoding_mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_left_to_encode() {
+        let convert = |pcm: Vec<f32>, options| {
+            let chunk = DecodedChunk {
+                pcm,
+                channels: 1,
+                sample_rate: OUT_SAMPLE_RATE,
+            };
+            conversion_error(opusify_pcm(std::iter::once(chunk), options))
+        };
+
+        let silence_trim = Options {
+            silence_trim: Some(SilenceTrim {
+                threshold_dbfs: -60.0,
+                min_duration: Duration::from_millis(100),
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            convert(vec![0.0; 48000], silence_trim),
+            Error::NoAudio
+        ));
+
+        let time_range = Options {
+            time_range: TimeRange {
+                start: Duration::from_secs(2),
+                end: None,
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            convert(vec![0.5; 48000], time_range),
+            Error::NoAudio
+        ));
+    }
+}
@@ -242,7 +242,7 @@ const _: () = {
 extern "C" {
     pub fn mp3dec_init(dec: *mut mp3dec_t);
 }
-pub type mp3d_sample_t = i16;
+pub type mp3d_sample_t = f32;
 extern "C" {
     pub fn mp3dec_decode_frame(
         dec: *mut mp3dec_t,
@@ -252,3 +252,6 @@ extern "C" {
         info: *mut mp3dec_frame_info_t,
     ) -> ::std::os::raw::c_int;
 }
+extern "C" {
+    pub fn mp3dec_f32_to_s16(in_: *const f32, out: *mut i16, num_samples: ::std::os::raw::c_int);
+}
@@ -1,12 +1,23 @@
 use crate::{decoded_chunk::DecodedChunk, minimp3_bindings::*};
-use std::sync::mpsc;
+use std::{sync::mpsc, time::Duration};
 
-pub fn decode_mp3(in_rx: mpsc::Receiver<bytes::Bytes>) -> mpsc::Receiver<DecodedChunk> {
+/// Frames decoded before `start` anyway, to refill the bit reservoir after skipping
+const RESERVOIR_FRAMES: f64 = 10.0;
+
+/// Samples before `start` are dropped. Frames well before it are skipped by their header,
+/// without being decoded.
+pub fn decode_mp3(
+    in_rx: mpsc::Receiver<bytes::Bytes>,
+    start: Duration,
+) -> mpsc::Receiver<DecodedChunk> {
     let (out_tx, out_rx) = mpsc::channel();
 
     std::thread::spawn(move || {
         let now = std::time::Instant::now();
         let mut sample_sum = 0;
+        let start = start.as_secs_f64();
+        // In seconds, because the sample rate can change between frames
+        let mut position = 0f64;
 
         let mut mp3_decoder = unsafe {
             let mut mp3_decoder: mp3dec_t = std::mem::zeroed();
@@ -20,16 +31,19 @@ pub fn decode_mp3(in_rx: mpsc::Receiver<bytes::Bytes>) -> mpsc::Receiver<Decoded
         let mut head = 0;
         let mut tail = 0;
 
-        let mut out_pcm_buffer = vec![0i16; MINIMP3_MAX_SAMPLES_PER_FRAME as usize];
+        let mut out_pcm_buffer = vec![0f32; MINIMP3_MAX_SAMPLES_PER_FRAME as usize];
 
-        let mut decode_and_send =
-            |mp3_input_buffer: &mut Vec<u8>, head: &mut usize, tail: &mut usize| {
+        let mut decode_and_send = |mp3_input_buffer: &mut Vec<u8>,
+                                   head: &mut usize,
+                                   tail: &mut usize| {
+            if position < start {
+                // Null pcm only parses the header
                 let samples = unsafe {
                     mp3dec_decode_frame(
                         &mut mp3_decoder,
                         mp3_input_buffer.as_ptr().add(*head),
                         (*tail - *head) as _,
-                        out_pcm_buffer.as_mut_ptr(),
+                        std::ptr::null_mut(),
                         &mut info,
                     )
                 };
@@ -39,28 +53,68 @@ pub fn decode_mp3(in_rx: mpsc::Receiver<bytes::Bytes>) -> mpsc::Receiver<Decoded
                     return true;
                 }
 
-                if samples != 0 {
-                    let pcm_count = (samples * info.channels) as usize;
-                    let pcm = out_pcm_buffer[..pcm_count].to_vec();
-                    sample_sum += samples;
-
-                    if out_tx
-                        .send(DecodedChunk {
-                            pcm,
-                            channels: info.channels as _,
-                            sample_rate: info.hz as _,
-                        })
-                        .is_err()
-                    {
-                        return false;
-                    }
+                if samples == 0 {
+                    // skipped non-frame data
+                    *head += info.frame_bytes as usize;
+                    return true;
                 }
 
-                *head += info.frame_bytes as usize;
+                let frame_duration = samples as f64 / info.hz as f64;
+                if position + frame_duration * (1.0 + RESERVOIR_FRAMES) <= start {
+                    position += frame_duration;
+                    *head += info.frame_bytes as usize;
+                    return true;
+                }
+            }
 
-                true
+            let samples = unsafe {
+                mp3dec_decode_frame(
+                    &mut mp3_decoder,
+                    mp3_input_buffer.as_ptr().add(*head),
+                    (*tail - *head) as _,
+                    out_pcm_buffer.as_mut_ptr(),
+                    &mut info,
+                )
             };
 
+            if samples == 0 && info.frame_bytes == 0 {
+                // not enough data
+                return true;
+            }
+
+            if samples != 0 {
+                let frame_start = position;
+                position += samples as f64 / info.hz as f64;
+
+                if position <= start {
+                    *head += info.frame_bytes as usize;
+                    return true;
+                }
+
+                let skip_samples = (((start - frame_start) * info.hz as f64).round().max(0.0)
+                    as usize)
+                    .min(samples as usize);
+                let pcm_count = (samples * info.channels) as usize;
+                let pcm = out_pcm_buffer[skip_samples * info.channels as usize..pcm_count].to_vec();
+                sample_sum += samples;
+
+                if out_tx
+                    .send(DecodedChunk {
+                        pcm,
+                        channels: info.channels as _,
+                        sample_rate: info.hz as _,
+                    })
+                    .is_err()
+                {
+                    return false;
+                }
+            }
+
+            *head += info.frame_bytes as usize;
+
+            true
+        };
+
         while let Ok(chunk) = in_rx.recv() {
             mp3_input_buffer.extend_from_slice(&chunk);
             tail += chunk.len();
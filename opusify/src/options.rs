@@ -0,0 +1,284 @@
+use crate::{Chunking, Decoder, PcmFilter};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Options {
+    /// Tried in order before the built-in MP3 decoder, the first one whose probe
+    /// matches decodes the input.
+    pub decoders: Vec<Box<dyn Decoder>>,
+    /// Reads the input as headerless PCM instead of probing it.
+    pub raw_pcm: Option<RawPcmFormat>,
+    pub channel_layout: ChannelLayout,
+    /// Applied after resampling, before encoding.
+    pub channel_conversion: Option<ChannelConversion>,
+    pub resample_quality: ResampleQuality,
+    /// Round to 16-bit right before encoding. `None` keeps the full f32 precision.
+    pub quantization: Option<Quantization>,
+    /// Only this part of the input is encoded, cut at sample precision.
+    pub time_range: TimeRange,
+    pub silence_trim: Option<SilenceTrim>,
+    /// Constant gain, applied after resampling and channel conversion.
+    pub gain_db: f32,
+    /// From the start of the encoded output, after trimming.
+    pub fade_in: Option<Fade>,
+    /// To the end of the encoded output, after trimming.
+    pub fade_out: Option<Fade>,
+    /// Run in order after channel conversion, before the gain and fades.
+    pub filters: Vec<Box<dyn PcmFilter>>,
+    /// Decode the output and compare it with what went into the encoder,
+    /// see [`crate::Conversion::quality`].
+    pub quality_check: bool,
+    pub encoding_mode: EncodingMode,
+    /// For [`EncodingMode::Parallel`], [`crate::Chunking::from_env`] if `None`.
+    pub chunking: Option<Chunking>,
+    pub encoder_settings: EncoderSettings,
+}
+
+/// How the encoding is spread over threads.
+///
+/// Both modes give the same output on every run for the same input and options.
+/// Across machines that also takes the same libopus build, as libopus picks its
+/// SIMD code at runtime by CPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncodingMode {
+    /// Jobs split by [`Options::chunking`], each with a fresh encoder, on the global
+    /// rayon thread pool. The output doesn't depend on the order the jobs finish in.
+    #[default]
+    Parallel,
+    /// One encoder for the whole input, on a single thread.
+    Serial,
+}
+
+/// Encoder decisions fixed for the whole stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderSettings {
+    /// `None` leaves it to libopus.
+    ///
+    /// Every parallel job starts with a fresh encoder. When they can't decide differently
+    /// from the encoder before them, there is less to settle at the job boundaries,
+    /// at the cost of the encoder adapting to the content. Measured with
+    /// [`crate::compare_chunking`] on synthetic stereo music at 48 kb/s, forcing CELT took
+    /// the mean SNR against serial encoding around the boundaries from 13 to 16.5 dB.
+    /// Forcing only the bandwidth made no difference there.
+    pub mode: Option<OpusMode>,
+    /// Forced for every frame. `None` leaves it to libopus. libopus still lowers it
+    /// where the band can't be coded, in SILK or hybrid mode at very low bitrates and
+    /// above half the input sample rate.
+    pub bandwidth: Option<Bandwidth>,
+    /// In bits per second for all channels together. `None` leaves it to libopus,
+    /// which picks one from the sample rate and the channel count.
+    pub bitrate: Option<u32>,
+    pub frame_duration: FrameDuration,
+}
+
+/// Of every Opus packet. Longer frames spend fewer bits on packet overhead, which
+/// matters more than latency for files. [`crate::Chunking`] is counted in frames,
+/// so the jobs get longer with the frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FrameDuration {
+    Ms2_5,
+    Ms5,
+    #[default]
+    Ms10,
+    Ms20,
+    Ms40,
+    Ms60,
+    /// Longer than 60 ms needs libopus 1.2 or later
+    Ms80,
+    Ms100,
+    Ms120,
+}
+
+impl FrameDuration {
+    /// Samples per channel at `sample_rate`, one of the Opus sample rates.
+    pub fn frame_size(self, sample_rate: usize) -> usize {
+        // Per 2.5 ms
+        let quarter_frames = match self {
+            FrameDuration::Ms2_5 => 1,
+            FrameDuration::Ms5 => 2,
+            FrameDuration::Ms10 => 4,
+            FrameDuration::Ms20 => 8,
+            FrameDuration::Ms40 => 16,
+            FrameDuration::Ms60 => 24,
+            FrameDuration::Ms80 => 32,
+            FrameDuration::Ms100 => 40,
+            FrameDuration::Ms120 => 48,
+        };
+        quarter_frames * sample_rate / 400
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpusMode {
+    /// Speech codec, up to wideband
+    SilkOnly,
+    Hybrid,
+    /// Transform codec, converges quickest after a fresh start
+    CeltOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bandwidth {
+    /// 4 kHz
+    Narrowband,
+    /// 6 kHz
+    Mediumband,
+    /// 8 kHz
+    Wideband,
+    /// 12 kHz
+    SuperWideband,
+    /// 20 kHz
+    Fullband,
+}
+
+/// New metadata for [`crate::remux`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagEdit {
+    /// Replace every user comment of the input, as `NAME=value`.
+    pub comments: Vec<String>,
+    /// Replace the output gain of the OpusHead. `None` keeps it.
+    pub output_gain_db: Option<f32>,
+}
+
+/// Layout of headerless PCM input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawPcmFormat {
+    pub sample_format: SampleFormat,
+    pub endianness: Endianness,
+    pub sample_rate: usize,
+    /// Interleaved
+    pub channels: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// Signed 16-bit integer
+    S16,
+    /// Signed 24-bit integer, packed in 3 bytes
+    S24,
+    /// 32-bit float, nominally in -1.0..=1.0
+    F32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+/// How the input channels should be interpreted.
+/// https://wiki.xiph.org/OggOpus#Channel_Mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelLayout {
+    /// Mono, stereo, or 3 to 8 speaker channels in Vorbis order.
+    /// Uses mapping family 0 for mono/stereo and family 1 otherwise.
+    #[default]
+    Speakers,
+    /// (N+1)² ambisonic channels in ACN/SN3D order, optionally followed by
+    /// a non-diegetic stereo pair. Uses mapping family 2.
+    Ambisonics,
+    /// Same input as [`ChannelLayout::Ambisonics`], but encoded with the
+    /// projection encoder and a demixing matrix. Uses mapping family 3.
+    AmbisonicsProjection,
+}
+
+impl ChannelLayout {
+    /// Ambisonic order of `channels`, ignoring the non-diegetic stereo pair.
+    /// `None` if `channels` is neither (N+1)² nor (N+1)² + 2 for N >= 1.
+    pub fn ambisonic_order(channels: usize) -> Option<usize> {
+        let order_plus_one = (channels as f64).sqrt() as usize;
+        let ambisonic_channels = order_plus_one * order_plus_one;
+        let non_diegetic_channels = channels - ambisonic_channels;
+
+        if order_plus_one < 2 || !(non_diegetic_channels == 0 || non_diegetic_channels == 2) {
+            return None;
+        }
+
+        Some(order_plus_one - 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelConversion {
+    /// Mix every channel down to one. Mono input is passed through.
+    Mono(MonoDownmix),
+    /// Duplicate mono input into both channels. Stereo input is passed through.
+    MonoToStereo,
+    /// Keep only the left channel of a stereo input. Mono input is passed through.
+    LeftOnly,
+    /// Keep only the right channel of a stereo input. Mono input is passed through.
+    RightOnly,
+    /// ITU-R BS.775 downmix of 5.1 in Vorbis channel order, dropping the LFE.
+    Surround51ToStereo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonoDownmix {
+    /// Every channel has the same weight, 1 / channels.
+    Average,
+    /// One weight per input channel, in input channel order.
+    Coefficients(Vec<f32>),
+}
+
+/// Resampler used when the input is not at a sample rate Opus can encode directly.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ResampleQuality {
+    /// Linear interpolation without anti-aliasing, for bulk speech.
+    Fast,
+    /// FFT based resampling.
+    #[default]
+    Balanced,
+    /// Long windowed sinc with cubic interpolation, for archival.
+    Best,
+    /// Windowed sinc with explicit parameters.
+    Sinc(SincParameters),
+}
+
+/// See `rubato::SincInterpolationParameters`.
+/// Cubic interpolation and a Blackman-Harris² window are always used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SincParameters {
+    pub sinc_len: usize,
+    /// Relative to the lower Nyquist frequency of input and output.
+    pub f_cutoff: f32,
+    pub oversampling_factor: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quantization {
+    /// Add TPDF dither before rounding.
+    pub dither: bool,
+    /// Compress peaks above about -0.9 dBFS instead of clipping them.
+    pub soft_limit: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: Duration,
+    /// `None` for the end of the input. Use `start + duration` for a start/duration range.
+    pub end: Option<Duration>,
+}
+
+/// Trims leading and trailing silence, after [`Options::time_range`] is applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceTrim {
+    /// Frames where every channel is below this are silent, e.g. -60.0.
+    pub threshold_dbfs: f32,
+    /// Shorter runs of silence are kept.
+    pub min_duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fade {
+    pub duration: Duration,
+    pub curve: FadeCurve,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    /// Equal steps in dB, from -60 dB. Sounds more even than linear on music.
+    Logarithmic,
+}
@@ -0,0 +1,425 @@
+use super::{
+    ogg_reader::{Packet, PacketReader},
+    wrapper::*,
+};
+use crate::{decoded_chunk::DecodedChunk, ChannelLayout, Decoder, OUT_SAMPLE_RATE};
+use anyhow::bail;
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Ogg Opus input. Only the first logical stream is read.
+pub struct OggOpusDecoder {
+    /// Filled with the user comments of the input before any PCM is sent
+    comments: Arc<Mutex<Vec<String>>>,
+    /// One of the Opus sample rates, usually 48 kHz
+    sample_rate: usize,
+    /// What the output is encoded with, to reject speaker input for ambisonic output
+    /// and the other way around. `None` skips the check.
+    channel_layout: Option<ChannelLayout>,
+}
+
+impl OggOpusDecoder {
+    pub fn new(
+        comments: Arc<Mutex<Vec<String>>>,
+        sample_rate: usize,
+        channel_layout: Option<ChannelLayout>,
+    ) -> Self {
+        Self {
+            comments,
+            sample_rate,
+            channel_layout,
+        }
+    }
+
+    /// `start_granule_position` is at 48 kHz, `position` counts the decoded samples per channel
+    /// so far, including the pre-skip. Returns `false` once the stream or the output ended.
+    fn decode_packet(
+        &self,
+        decoder: &mut OpusDecoderWrapper,
+        head: &OpusHead,
+        packet: &Packet,
+        start_granule_position: u64,
+        position: &mut u64,
+        out_tx: &mpsc::Sender<DecodedChunk>,
+    ) -> anyhow::Result<bool> {
+        let channels = head.channels;
+        // Pre-skip and granule positions are counted at 48 kHz
+        let sample_rate = self.sample_rate;
+        let to_sample_rate = |samples: u64| samples * sample_rate as u64 / OUT_SAMPLE_RATE as u64;
+
+        let mut pcm = decoder.decode(&packet.data)?;
+        let frames = (pcm.len() / channels) as u64;
+
+        // https://wiki.xiph.org/OggOpus#Granule_Position
+        // The last page can end before the last packet does, to trim the padding.
+        if packet.end_of_stream {
+            if let Some(granule_position) = packet.granule_position {
+                let kept_frames =
+                    to_sample_rate(granule_position.saturating_sub(start_granule_position))
+                        .saturating_sub(*position)
+                        .min(frames);
+                pcm.truncate(kept_frames as usize * channels);
+            }
+        }
+
+        let skip_frames = to_sample_rate(head.pre_skip as u64)
+            .saturating_sub(*position)
+            .min(frames);
+        *position += frames;
+        pcm.drain(..(skip_frames as usize * channels).min(pcm.len()));
+
+        if head.output_gain != 0 {
+            // Q7.8 in dB
+            let gain = 10f32.powf(head.output_gain as f32 / (20.0 * 256.0));
+            pcm.iter_mut().for_each(|sample| *sample *= gain);
+        }
+
+        if !pcm.is_empty()
+            && out_tx
+                .send(DecodedChunk {
+                    pcm,
+                    channels,
+                    sample_rate,
+                })
+                .is_err()
+        {
+            return Ok(false);
+        }
+
+        Ok(!packet.end_of_stream)
+    }
+}
+
+impl Decoder for OggOpusDecoder {
+    fn probe(&self, head: &[u8]) -> bool {
+        head.starts_with(b"OggS") && head.get(28..36) == Some(b"OpusHead")
+    }
+
+    fn decode(
+        &mut self,
+        in_rx: mpsc::Receiver<bytes::Bytes>,
+        out_tx: mpsc::Sender<DecodedChunk>,
+    ) -> anyhow::Result<()> {
+        let mut reader = PacketReader::new();
+        let mut serial = None;
+        let mut head = None;
+        let mut decoder = None;
+        // Audio packets before the first granule position, which gives the start of the stream
+        let mut pending = Vec::new();
+        let mut start_granule_position = None;
+        // Decoded samples per channel, including the pre-skip
+        let mut position = 0u64;
+
+        while let Ok(bytes) = in_rx.recv() {
+            reader.push(&bytes)?;
+
+            while let Some(packet) = reader.next_packet() {
+                if *serial.get_or_insert(packet.serial) != packet.serial {
+                    continue;
+                }
+
+                let Some(head) = head.as_ref() else {
+                    let parsed = OpusHead::parse(&packet.data)?;
+                    if let Some(channel_layout) = self.channel_layout {
+                        let is_ambisonic_input = matches!(parsed.channel_mapping.family, 2 | 3);
+                        if is_ambisonic_input != (channel_layout != ChannelLayout::Speakers) {
+                            bail!(crate::Error::ChannelConversion {
+                                reason:
+                                    "channel layout doesn't match the mapping family of the input",
+                            });
+                        }
+                    }
+                    head = Some(parsed);
+                    continue;
+                };
+
+                let Some(decoder) = decoder.as_mut() else {
+                    *self.comments.lock().unwrap() = parse_comments(&packet.data)?;
+                    decoder = Some(OpusDecoderWrapper::new(
+                        head.channels,
+                        self.sample_rate,
+                        &head.channel_mapping,
+                    )?);
+                    continue;
+                };
+
+                pending.push(packet);
+                let start = match start_granule_position {
+                    Some(start_granule_position) => start_granule_position,
+                    None => {
+                        let Some(start) = start_of_stream(&pending)? else {
+                            continue;
+                        };
+                        *start_granule_position.insert(start)
+                    }
+                };
+
+                for packet in pending.drain(..) {
+                    if !self.decode_packet(decoder, head, &packet, start, &mut position, &out_tx)? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        let (Some(head), Some(decoder)) = (head.as_ref(), decoder.as_mut()) else {
+            bail!(crate::Error::OpusDecode {
+                reason: "missing OpusHead or OpusTags",
+            });
+        };
+
+        // Cut off before any granule position
+        for packet in pending {
+            if !self.decode_packet(decoder, head, &packet, 0, &mut position, &out_tx)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// https://wiki.xiph.org/OggOpus#Granule_Position
+/// The granule position of the first page with audio, minus the samples that ended on it.
+/// `None` until `pending` ends with a granule position. A stream that ends on its first page
+/// starts at 0, its granule position only trims the end.
+pub(super) fn start_of_stream(pending: &[Packet]) -> Result<Option<u64>, crate::Error> {
+    let Some(last) = pending.last() else {
+        return Ok(None);
+    };
+    let Some(granule_position) = last.granule_position else {
+        return Ok(None);
+    };
+    if last.end_of_stream {
+        return Ok(Some(0));
+    }
+
+    let mut samples = 0;
+    for packet in pending {
+        samples += packet_samples(&packet.data)? as u64;
+    }
+    Ok(Some(granule_position.saturating_sub(samples)))
+}
+
+/// Decodes a whole Ogg Opus stream in memory, on the calling thread.
+pub fn decode_ogg_opus(input: &[u8], sample_rate: usize) -> anyhow::Result<DecodedChunk> {
+    let (in_tx, in_rx) = mpsc::channel();
+    in_tx.send(bytes::Bytes::copy_from_slice(input))?;
+    drop(in_tx);
+
+    let (out_tx, out_rx) = mpsc::channel();
+    OggOpusDecoder::new(Default::default(), sample_rate, None).decode(in_rx, out_tx)?;
+
+    let mut decoded = DecodedChunk {
+        pcm: Vec::new(),
+        channels: 0,
+        sample_rate,
+    };
+    for chunk in out_rx {
+        decoded.channels = chunk.channels;
+        decoded.pcm.extend(chunk.pcm);
+    }
+    if decoded.pcm.is_empty() {
+        bail!(crate::Error::OpusDecode {
+            reason: "no audio packets",
+        });
+    }
+
+    Ok(decoded)
+}
+
+/// https://wiki.xiph.org/OggOpus#ID_Header
+struct OpusHead {
+    channels: usize,
+    /// At 48 kHz
+    pre_skip: usize,
+    /// Q7.8 in dB
+    output_gain: i16,
+    channel_mapping: ChannelMapping,
+}
+
+impl OpusHead {
+    fn parse(packet: &[u8]) -> Result<Self, crate::Error> {
+        let invalid = || crate::Error::OpusDecode {
+            reason: "invalid OpusHead",
+        };
+        if packet.len() < 19 || !packet.starts_with(b"OpusHead") || packet[8] >> 4 != 0 {
+            return Err(invalid());
+        }
+
+        let channels = packet[9] as usize;
+        let pre_skip = u16::from_le_bytes([packet[10], packet[11]]) as usize;
+        let output_gain = i16::from_le_bytes([packet[16], packet[17]]);
+        let family = packet[18];
+
+        let channel_mapping = if family == 0 {
+            if !(1..=2).contains(&channels) {
+                return Err(invalid());
+            }
+            ChannelMapping {
+                family,
+                streams: 1,
+                coupled_streams: (channels - 1) as u8,
+                mapping: Vec::new(),
+                output_gain,
+            }
+        } else {
+            let streams = *packet.get(19).ok_or_else(invalid)?;
+            let coupled_streams = *packet.get(20).ok_or_else(invalid)?;
+            let mapping_len = if family == 3 {
+                // Demixing matrix, RFC 8486 section 3.1
+                2 * channels * (streams as usize + coupled_streams as usize)
+            } else {
+                channels
+            };
+            ChannelMapping {
+                family,
+                streams,
+                coupled_streams,
+                mapping: packet
+                    .get(21..21 + mapping_len)
+                    .ok_or_else(invalid)?
+                    .to_vec(),
+                output_gain,
+            }
+        };
+
+        if channels == 0 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            channels,
+            pre_skip,
+            output_gain,
+            channel_mapping,
+        })
+    }
+}
+
+/// https://wiki.xiph.org/OggOpus#Comment_Header
+fn parse_comments(packet: &[u8]) -> Result<Vec<String>, crate::Error> {
+    let invalid = || crate::Error::OpusDecode {
+        reason: "invalid OpusTags",
+    };
+    if !packet.starts_with(b"OpusTags") {
+        return Err(invalid());
+    }
+
+    let mut rest = &packet[8..];
+    let read_u32 = |rest: &mut &[u8]| -> Result<usize, crate::Error> {
+        let bytes = rest.get(..4).ok_or_else(invalid)?;
+        let value = u32::from_le_bytes(bytes.try_into().unwrap()) as usize;
+        *rest = &rest[4..];
+        Ok(value)
+    };
+
+    let vendor_len = read_u32(&mut rest)?;
+    rest = rest.get(vendor_len..).ok_or_else(invalid)?;
+
+    let comment_count = read_u32(&mut rest)?;
+    let mut comments = Vec::new();
+    for _ in 0..comment_count {
+        let len = read_u32(&mut rest)?;
+        let comment = rest.get(..len).ok_or_else(invalid)?;
+        comments.push(String::from_utf8_lossy(comment).into_owned());
+        rest = &rest[len..];
+    }
+
+    Ok(comments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{quality::snr_db, Options};
+
+    /// 440 Hz in the left channel and 1 kHz in the right, at half scale.
+    fn tones(sample_rate: usize, channels: usize, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let time = i as f64 / sample_rate as f64;
+                [440.0, 1000.0][..channels].iter().map(move |frequency| {
+                    (0.5 * (2.0 * std::f64::consts::PI * frequency * time).sin()) as f32
+                })
+            })
+            .collect()
+    }
+
+    fn encode(sample_rate: usize, channels: usize, frames: usize) -> Vec<u8> {
+        let chunk = DecodedChunk {
+            pcm: tones(sample_rate, channels, frames),
+            channels,
+            sample_rate,
+        };
+        crate::opusify_pcm(std::iter::once(chunk), Options::default())
+            .unwrap()
+            .output
+    }
+
+    /// The shift within ±20 frames where `decoded` matches `source` best.
+    fn best_lag(source: &[f32], decoded: &[f32], channels: usize) -> isize {
+        (-20isize..=20)
+            .max_by(|&a, &b| {
+                let snr = |lag: isize| {
+                    let shift = lag.unsigned_abs() * channels;
+                    let len = source.len() - shift;
+                    if lag >= 0 {
+                        snr_db(&source[..len], &decoded[shift..])
+                    } else {
+                        snr_db(&source[shift..], &decoded[..len])
+                    }
+                };
+                snr(a).total_cmp(&snr(b))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip_length() {
+        for sample_rate in [8000, 16000, 44100, 48000] {
+            for channels in [1, 2] {
+                for frames in [1, 100, 479, 481, sample_rate / 2, sample_rate + 123] {
+                    let decoded =
+                        crate::decode_opus(&encode(sample_rate, channels, frames)).unwrap();
+
+                    assert_eq!(decoded.channels, channels);
+                    assert_eq!(
+                        decoded.pcm.len() / channels,
+                        (frames * OUT_SAMPLE_RATE).div_ceil(sample_rate),
+                        "{frames} frames at {sample_rate} Hz, {channels} channels"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_content() {
+        for sample_rate in [8000, 16000, 48000] {
+            for channels in [1, 2] {
+                for frames in [481, sample_rate + 123] {
+                    let source = tones(sample_rate, channels, frames);
+                    let encoded = encode(sample_rate, channels, frames);
+                    let decoded = decode_ogg_opus(&encoded, sample_rate).unwrap().pcm;
+                    assert_eq!(decoded.len(), source.len());
+
+                    let case = format!("{frames} frames at {sample_rate} Hz, {channels} channels");
+                    assert_eq!(best_lag(&source, &decoded, channels), 0, "{case}");
+                    // SILK at the low rates keeps the spectrum rather than the waveform
+                    if sample_rate == OUT_SAMPLE_RATE {
+                        let snr = snr_db(&source, &decoded);
+                        assert!(snr > 25.0, "{case}: {snr} dB");
+                    }
+                }
+            }
+        }
+
+        // Resampled to 48 kHz
+        for frames in [22050, 44100 + 123] {
+            let decoded = crate::decode_opus(&encode(44100, 2, frames)).unwrap().pcm;
+            let source = tones(OUT_SAMPLE_RATE, 2, decoded.len() / 2);
+            let snr = snr_db(&source, &decoded);
+            assert!(snr > 20.0, "{frames} frames at 44100 Hz: {snr} dB");
+        }
+    }
+}
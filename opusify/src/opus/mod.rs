@@ -1,65 +1,215 @@
 //! Inspired by https://github.com/enzo1982/superfast#superfast-codecs
 
+mod decode;
 mod ogg;
+mod ogg_reader;
+mod remux;
 mod wrapper;
 
-use crate::{decoded_chunk::DecodedChunk, OUT_SAMPLE_RATE};
+use crate::{
+    decoded_chunk::DecodedChunk, ChannelLayout, EncoderSettings, EncodingMode, FrameDuration,
+    OUT_SAMPLE_RATE,
+};
+pub use decode::{decode_ogg_opus, OggOpusDecoder};
+pub use remux::remux;
 use std::{
     collections::BTreeMap,
-    sync::mpsc::{self},
+    sync::{
+        mpsc::{self},
+        Arc, Mutex,
+    },
 };
 use wrapper::*;
 
 // it's okay to use a constant here because it has only one stream
 const SERIAL: u32 = 12345;
-// const FRAME_SIZE: usize = 2880;
-const FRAME_SIZE: usize = 480;
+/// Left padding below this mean square power can be shortened, about -60 dBFS
+const QUIET_PADDING_POWER: f32 = 1e-6;
 
 pub fn encode_to_ogg_opus(
     in_rx: mpsc::Receiver<DecodedChunk>,
     err_tx: mpsc::Sender<crate::Error>,
+    channel_layout: ChannelLayout,
+    comments: Arc<Mutex<Vec<String>>>,
+    encoder_settings: EncoderSettings,
+    encoding_mode: EncodingMode,
+    chunking: Chunking,
 ) -> anyhow::Result<mpsc::Receiver<bytes::Bytes>> {
-    let first_chunk = in_rx.recv()?;
+    let Ok(first_chunk) = in_rx.recv() else {
+        return Err(crate::Error::NoAudio.into());
+    };
 
     let channels = first_chunk.channels;
+    let sample_rate = first_chunk.sample_rate;
+
+    let encoder = OpusEncoderWrapper::new(channels, sample_rate, channel_layout)?;
+    let lookahead = encoder.lookahead()?;
+    let channel_mapping = encoder.channel_mapping().clone();
+    if encoding_mode == EncodingMode::Parallel {
+        chunking.check(
+            encoder_settings.frame_duration.frame_size(sample_rate),
+            lookahead,
+        )?;
+    }
 
     let (encoded_tx, encoded_rx) = mpsc::channel();
-    start_spawner_thread(in_rx, encoded_tx, first_chunk);
-    let out_rx = start_ogg_writer_thread(encoded_rx, channels);
+    match encoding_mode {
+        EncodingMode::Parallel => start_spawner_thread(
+            in_rx,
+            encoded_tx,
+            err_tx,
+            first_chunk,
+            channel_layout,
+            encoder_settings,
+            lookahead,
+            chunking,
+        ),
+        EncodingMode::Serial => start_serial_encoder_thread(
+            in_rx,
+            encoded_tx,
+            err_tx,
+            first_chunk,
+            channel_layout,
+            encoder_settings,
+            lookahead,
+        ),
+    }
+    let out_rx = start_ogg_writer_thread(
+        encoded_rx,
+        channels,
+        sample_rate,
+        lookahead,
+        channel_mapping,
+        comments,
+    );
 
     Ok(out_rx)
 }
 
-fn padding_frames() -> usize {
-    std::env::var("PADDING_FRAMES")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(8)
+/// How the input is split into encoding jobs. Every job encodes its middle frames with
+/// a fresh encoder, plus padding frames on both sides that are thrown away,
+/// so the encoder state has settled by the middle frames. Frames are
+/// [`crate::FrameDuration`] long.
+///
+/// More padding costs encoding time for every job. See [`crate::compare_chunking`] to
+/// measure what it buys for a given input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunking {
+    pub padding_frames: usize,
+    pub middle_frames: usize,
+    /// Left padding for jobs where the padding is near silent, about -60 dBFS.
+    /// Both encoders have little state left after silence, so less padding is needed
+    /// to converge there. Capped at `padding_frames`. On music with a gap every other
+    /// half second, 2 frames kept the boundaries after silence as close to serial
+    /// encoding as 8 did, for 6 frames less encoding in each of those jobs.
+    pub quiet_padding_frames: usize,
+}
+
+impl Chunking {
+    /// `PADDING_FRAMES`, `MIDDLE_FRAMES` and `QUIET_PADDING_FRAMES`, 8, 96 and the padding
+    /// frames if not set.
+    ///
+    /// Measured with [`crate::compare_chunking`] on 30 s of synthetic 48 kHz stereo music
+    /// at 10 ms frames: the mean SNR against serial encoding around a boundary goes from
+    /// 7 dB without padding to 15 dB at 2, 19 dB at 4 and 21 dB at 8 padding frames, where
+    /// the whole output is at 23.5 dB. From 4 frames on, the largest difference around
+    /// a boundary is no larger than anywhere else. 8 on 96 frames encodes 17% more frames
+    /// than serial encoding.
+    pub fn from_env() -> Self {
+        let padding_frames = env_or("PADDING_FRAMES", 8);
+        Self {
+            padding_frames,
+            middle_frames: env_or("MIDDLE_FRAMES", 96),
+            quiet_padding_frames: env_or("QUIET_PADDING_FRAMES", padding_frames),
+        }
+    }
+
+    /// Every job has to move the input forward, and the first boundary has to be past
+    /// the encoder delay.
+    fn check(&self, frame_size: usize, lookahead: usize) -> Result<(), crate::Error> {
+        if self.middle_frames == 0 {
+            return Err(crate::Error::InvalidChunking {
+                reason: "no middle frames",
+            });
+        }
+        if (self.padding_frames + self.middle_frames) * frame_size < lookahead {
+            return Err(crate::Error::InvalidChunking {
+                reason: "padding and middle frames are shorter than the encoder lookahead",
+            });
+        }
+        Ok(())
+    }
 }
 
-fn middle_frames() -> usize {
-    std::env::var("MIDDLE_FRAMES")
+/// Fails if the spawner can't split input of this format with `chunking`.
+pub fn check_chunking(
+    chunking: Chunking,
+    channels: usize,
+    sample_rate: usize,
+    channel_layout: ChannelLayout,
+    frame_duration: FrameDuration,
+) -> anyhow::Result<()> {
+    let lookahead = OpusEncoderWrapper::new(channels, sample_rate, channel_layout)?.lookahead()?;
+    chunking.check(frame_duration.frame_size(sample_rate), lookahead)?;
+    Ok(())
+}
+
+/// Where the encoding jobs hand over to a fresh encoder, in frames of the decoded output
+/// at `sample_rate`, for `frames` frames of input.
+pub fn job_boundaries(
+    frames: usize,
+    channels: usize,
+    sample_rate: usize,
+    channel_layout: ChannelLayout,
+    frame_duration: FrameDuration,
+    chunking: Chunking,
+) -> anyhow::Result<Vec<usize>> {
+    let lookahead = OpusEncoderWrapper::new(channels, sample_rate, channel_layout)?.lookahead()?;
+    let frame_size = frame_duration.frame_size(sample_rate);
+    chunking.check(frame_size, lookahead)?;
+
+    // The first job keeps its left padding, the others only their middle frames.
+    // The decoded output is behind the encoded frames by the lookahead.
+    let first_boundary = (chunking.padding_frames + chunking.middle_frames) * frame_size;
+    Ok((first_boundary..)
+        .step_by(chunking.middle_frames * frame_size)
+        .map(|boundary| boundary - lookahead)
+        .take_while(|&boundary| boundary < frames)
+        .collect())
+}
+
+fn env_or(key: &str, default: usize) -> usize {
+    std::env::var(key)
         .ok()
         .and_then(|s| s.parse().ok())
-        .unwrap_or(96)
+        .unwrap_or(default)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_spawner_thread(
     in_rx: mpsc::Receiver<DecodedChunk>,
     encoded_tx: mpsc::Sender<Encoded>,
+    err_tx: mpsc::Sender<crate::Error>,
     first_chunk: DecodedChunk,
+    channel_layout: ChannelLayout,
+    encoder_settings: EncoderSettings,
+    lookahead: usize,
+    chunking: Chunking,
 ) {
     std::thread::spawn(move || {
         let now = std::time::Instant::now();
         let channels = first_chunk.channels;
+        let sample_rate = first_chunk.sample_rate;
+        let frame_size = encoder_settings.frame_duration.frame_size(sample_rate);
 
-        let left_padding_frames = padding_frames();
-        let middle_frames = middle_frames();
-        let right_padding_frames = padding_frames();
+        let left_padding_frames = chunking.padding_frames;
+        let middle_frames = chunking.middle_frames;
+        let right_padding_frames = chunking.padding_frames;
 
         let expected_encode_pcm_len =
-            (left_padding_frames + middle_frames + right_padding_frames) * channels * FRAME_SIZE;
-        let mut pcms: Vec<i16> = Vec::with_capacity(expected_encode_pcm_len);
+            (left_padding_frames + middle_frames + right_padding_frames) * channels * frame_size;
+        let mut pcms: Vec<f32> = Vec::with_capacity(expected_encode_pcm_len);
+        let mut total_pcm_len = first_chunk.pcm.len();
         pcms.extend(first_chunk.pcm);
 
         let mut is_first = true;
@@ -69,20 +219,37 @@ fn start_spawner_thread(
                 let Ok(encoded) = in_rx.recv() else {
                     break;
                 };
+                total_pcm_len += encoded.pcm.len();
                 pcms.extend(encoded.pcm);
             }
 
             let encode_pcm_len = pcms.len().min(expected_encode_pcm_len);
             let is_end = encode_pcm_len < expected_encode_pcm_len;
 
-            if is_end {
-                pcms.resize(expected_encode_pcm_len, 0);
-            }
-
-            assert!(pcms.len() >= expected_encode_pcm_len);
+            let request_pcm_len = if is_end {
+                // Zeros past the end, at least the lookahead, so the last samples leave the encoder.
+                // Only whole frames, the end is trimmed by the granule position of the last page.
+                let end_pcm_len =
+                    (encode_pcm_len + lookahead * channels).next_multiple_of(channels * frame_size);
+                pcms.resize(end_pcm_len, 0.0);
+                end_pcm_len
+            } else {
+                expected_encode_pcm_len
+            };
+
+            // The first job has no padding to throw away, its left padding is kept
+            let job_left_padding_frames =
+                if !is_first && is_quiet(&pcms[..left_padding_frames * channels * frame_size]) {
+                    chunking.quiet_padding_frames.min(left_padding_frames)
+                } else {
+                    left_padding_frames
+                };
+            let skip_pcm_len =
+                (left_padding_frames - job_left_padding_frames) * channels * frame_size;
 
             spawn_encoding_job(
                 encoded_tx.clone(),
+                err_tx.clone(),
                 EncodingRequest {
                     kind: match (is_first, is_end) {
                         (true, true) => EncodingRequestKind::FirstAndEnd,
@@ -90,10 +257,17 @@ fn start_spawner_thread(
                         (false, true) => EncodingRequestKind::End,
                         (false, false) => EncodingRequestKind::Middle,
                     },
-                    frame_size: FRAME_SIZE,
-                    pcm: pcms[..expected_encode_pcm_len].to_vec(),
+                    frame_size,
+                    pcm: pcms[skip_pcm_len..request_pcm_len].to_vec(),
                     channels,
+                    sample_rate,
+                    channel_layout,
+                    encoder_settings,
+                    left_padding_frames: job_left_padding_frames,
+                    middle_frames,
                     sequence_number,
+                    total_samples: is_end
+                        .then(|| total_pcm_len / channels * OUT_SAMPLE_RATE / sample_rate),
                 },
             );
             sequence_number += 1;
@@ -103,7 +277,7 @@ fn start_spawner_thread(
             }
 
             let removal_pcm_len = encode_pcm_len
-                - (left_padding_frames + right_padding_frames) * channels * FRAME_SIZE;
+                - (left_padding_frames + right_padding_frames) * channels * frame_size;
             pcms.drain(..removal_pcm_len);
 
             is_first = false;
@@ -115,11 +289,21 @@ fn start_spawner_thread(
 
 struct EncodingRequest {
     kind: EncodingRequestKind,
+    /// At `sample_rate`
     frame_size: usize,
-    /// (left_padding_frames + middle_frames + right_padding_frames) * channels * FRAME_SIZE
-    pcm: Vec<i16>,
+    /// (left_padding_frames + middle_frames + right_padding_frames) * channels * frame_size,
+    /// or up to the end plus the lookahead for the last request
+    pcm: Vec<f32>,
     channels: usize,
+    sample_rate: usize,
+    channel_layout: ChannelLayout,
+    encoder_settings: EncoderSettings,
+    /// Of this request, see [`Chunking::quiet_padding_frames`]
+    left_padding_frames: usize,
+    middle_frames: usize,
     sequence_number: usize,
+    /// Only on the last request, every input sample at 48 kHz
+    total_samples: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -130,13 +314,22 @@ enum EncodingRequestKind {
     FirstAndEnd,
 }
 
-fn spawn_encoding_job(encoded_tx: mpsc::Sender<Encoded>, request: EncodingRequest) {
+fn spawn_encoding_job(
+    encoded_tx: mpsc::Sender<Encoded>,
+    err_tx: mpsc::Sender<crate::Error>,
+    request: EncodingRequest,
+) {
     rayon::spawn_fifo(move || {
         let result: anyhow::Result<()> = (|| {
-            let mut encoder = OpusEncoderWrapper::new(request.channels, OUT_SAMPLE_RATE)?;
+            let mut encoder = OpusEncoderWrapper::new(
+                request.channels,
+                request.sample_rate,
+                request.channel_layout,
+            )?;
+            encoder.apply_settings(request.encoder_settings)?;
 
-            let left_padding_frames = padding_frames();
-            let middle_frames = middle_frames();
+            let left_padding_frames = request.left_padding_frames;
+            let middle_frames = request.middle_frames;
 
             let frame_pcm_len = request.channels * request.frame_size;
 
@@ -159,23 +352,117 @@ fn spawn_encoding_job(encoded_tx: mpsc::Sender<Encoded>, request: EncodingReques
 
                 packets.push(OpusPacket {
                     data: output,
-                    frame_size: request.frame_size,
+                    frame_size: request.frame_size * OUT_SAMPLE_RATE / request.sample_rate,
                 });
             }
 
             encoded_tx.send(Encoded {
                 sequence_number: request.sequence_number,
                 packets,
+                total_samples: request.total_samples,
             })?;
 
             Ok(())
         })();
+
+        // The writer stops at the missing sequence number, so the output is cut short
+        // rather than reordered
+        send_encoding_error(result, &err_tx);
+    });
+}
+
+/// Encodes everything with one encoder, as whole frames arrive.
+fn start_serial_encoder_thread(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+    encoded_tx: mpsc::Sender<Encoded>,
+    err_tx: mpsc::Sender<crate::Error>,
+    first_chunk: DecodedChunk,
+    channel_layout: ChannelLayout,
+    encoder_settings: EncoderSettings,
+    lookahead: usize,
+) {
+    std::thread::spawn(move || {
+        let now = std::time::Instant::now();
+        let result: anyhow::Result<()> = (|| {
+            let channels = first_chunk.channels;
+            let sample_rate = first_chunk.sample_rate;
+            let frame_size = encoder_settings.frame_duration.frame_size(sample_rate);
+            let frame_pcm_len = channels * frame_size;
+
+            let mut encoder = OpusEncoderWrapper::new(channels, sample_rate, channel_layout)?;
+            encoder.apply_settings(encoder_settings)?;
+
+            let mut total_pcm_len = first_chunk.pcm.len();
+            let mut pcms = first_chunk.pcm;
+            let mut sequence_number = 0;
+            loop {
+                let next_chunk = in_rx.recv().ok();
+                let is_end = next_chunk.is_none();
+                if let Some(chunk) = next_chunk {
+                    total_pcm_len += chunk.pcm.len();
+                    pcms.extend(chunk.pcm);
+                }
+
+                if is_end {
+                    // Same as the last parallel job, see `start_spawner_thread`
+                    let end_pcm_len =
+                        (pcms.len() + lookahead * channels).next_multiple_of(frame_pcm_len);
+                    pcms.resize(end_pcm_len, 0.0);
+                }
+
+                let encode_pcm_len = pcms.len() / frame_pcm_len * frame_pcm_len;
+                let packets = pcms[..encode_pcm_len]
+                    .chunks_exact(frame_pcm_len)
+                    .map(|frame| {
+                        Ok(OpusPacket {
+                            data: encoder.encode(frame, frame_size)?,
+                            frame_size: frame_size * OUT_SAMPLE_RATE / sample_rate,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, crate::Error>>()?;
+                pcms.drain(..encode_pcm_len);
+
+                encoded_tx.send(Encoded {
+                    sequence_number,
+                    packets,
+                    total_samples: is_end
+                        .then(|| total_pcm_len / channels * OUT_SAMPLE_RATE / sample_rate),
+                })?;
+                sequence_number += 1;
+
+                if is_end {
+                    return Ok(());
+                }
+            }
+        })();
+
+        println!(
+            "serial encoder thread finished, elapsed: {:?}",
+            now.elapsed()
+        );
+
+        send_encoding_error(result, &err_tx);
     });
 }
 
+fn is_quiet(pcm: &[f32]) -> bool {
+    pcm.iter().map(|sample| sample * sample).sum::<f32>() < QUIET_PADDING_POWER * pcm.len() as f32
+}
+
+/// A closed channel only means the conversion stopped early, so only encoder errors are sent.
+fn send_encoding_error(result: anyhow::Result<()>, err_tx: &mpsc::Sender<crate::Error>) {
+    if let Err(err) = result {
+        if let Ok(error) = err.downcast::<crate::Error>() {
+            let _ = err_tx.send(error);
+        }
+    }
+}
+
 struct Encoded {
     sequence_number: SequenceNumber,
     packets: OpusPackets,
+    /// Only on the last one, see [`EncodingRequest::total_samples`]
+    total_samples: Option<usize>,
 }
 
 type SequenceNumber = usize;
@@ -183,12 +470,17 @@ type OpusPackets = Vec<OpusPacket>;
 
 struct OpusPacket {
     data: Vec<u8>,
+    /// At 48 kHz, granule positions are always counted at 48 kHz
     frame_size: usize,
 }
 
 fn start_ogg_writer_thread(
     encoded_rx: mpsc::Receiver<Encoded>,
     channels: usize,
+    sample_rate: usize,
+    lookahead: usize,
+    channel_mapping: ChannelMapping,
+    comments: Arc<Mutex<Vec<String>>>,
 ) -> mpsc::Receiver<bytes::Bytes> {
     let (out_tx, out_rx) = mpsc::channel();
     std::thread::spawn(move || {
@@ -196,45 +488,34 @@ fn start_ogg_writer_thread(
 
         let _result: anyhow::Result<()> = (move || {
             let mut writer = ogg::PacketWriter::new(out_tx);
-            let lookahead = OpusEncoderWrapper::new(channels, OUT_SAMPLE_RATE)?.lookahead()?;
-
-            write_header(&mut writer, channels, lookahead)?;
-            write_tags(&mut writer)?;
+            // Pre-skip is counted at 48 kHz
+            let lookahead = lookahead * OUT_SAMPLE_RATE / sample_rate;
+
+            write_header(
+                &mut writer,
+                channels,
+                sample_rate,
+                lookahead,
+                &channel_mapping,
+            )?;
+            // The decoder fills the comments before its first chunk, so they are
+            // complete by the time the first chunk got here
+            write_tags(&mut writer, SERIAL, &comments.lock().unwrap())?;
 
             let mut sample_acc = 0;
 
-            let mut queue = BTreeMap::<SequenceNumber, OpusPackets>::new();
+            let mut queue = BTreeMap::<SequenceNumber, Encoded>::new();
             let mut next_sequence_number = 0;
-            let mut last_encoded = None;
 
             while let Ok(encoded) = encoded_rx.recv() {
-                // Keep the last packet to know when it's the end of the stream
-                let Some(encoded) = std::mem::replace(&mut last_encoded, Some(encoded)) else {
-                    continue;
-                };
-                queue.insert(encoded.sequence_number, encoded.packets);
+                queue.insert(encoded.sequence_number, encoded);
 
-                while let Some(packets) = queue.remove(&next_sequence_number) {
-                    handle_packets(&mut writer, packets, lookahead, &mut sample_acc, false)?;
+                while let Some(encoded) = queue.remove(&next_sequence_number) {
+                    handle_encoded(&mut writer, encoded, lookahead, &mut sample_acc)?;
                     next_sequence_number += 1;
                 }
             }
 
-            if let Some(encoded) = last_encoded {
-                queue.insert(encoded.sequence_number, encoded.packets);
-            }
-
-            while let Some(packets) = queue.remove(&next_sequence_number) {
-                handle_packets(
-                    &mut writer,
-                    packets,
-                    lookahead,
-                    &mut sample_acc,
-                    queue.is_empty(),
-                )?;
-                next_sequence_number += 1;
-            }
-
             println!("ogg writer thread finished, elapsed: {:?}", now.elapsed());
 
             Ok(())
@@ -244,35 +525,42 @@ fn start_ogg_writer_thread(
     out_rx
 }
 
-fn handle_packets(
+fn handle_encoded(
     writer: &mut ogg::PacketWriter,
-    packets: OpusPackets,
+    encoded: Encoded,
     lookahead: usize,
     sample_acc: &mut usize,
-    is_end: bool,
 ) -> anyhow::Result<()> {
-    for packet in packets {
-        write_opus_packet_to_ogg(writer, packet, lookahead, sample_acc, is_end)?;
+    let packet_count = encoded.packets.len();
+    for (index, packet) in encoded.packets.into_iter().enumerate() {
+        let end_samples = encoded.total_samples.filter(|_| index + 1 == packet_count);
+        write_opus_packet_to_ogg(writer, packet, lookahead, sample_acc, end_samples)?;
     }
     Ok(())
 }
 
+/// `end_samples` is only for the very last packet of the stream
 fn write_opus_packet_to_ogg(
     writer: &mut ogg::PacketWriter,
     packet: OpusPacket,
     lookahead: usize,
     sample_acc: &mut usize,
-    is_end: bool,
+    end_samples: Option<usize>,
 ) -> anyhow::Result<()> {
     *sample_acc += packet.frame_size;
 
     // https://wiki.xiph.org/OggOpus#Granule_Position
-    let granule_position = lookahead + *sample_acc;
+    // The stream starts at 0, and the pre-skip covers the encoder delay.
+    // The last one can be smaller than the decoded samples, to trim the padding at the end.
+    let granule_position = match end_samples {
+        Some(end_samples) => (lookahead + end_samples).min(*sample_acc),
+        None => *sample_acc,
+    };
 
     writer.write_packet(
         packet.data,
         SERIAL,
-        if is_end {
+        if end_samples.is_some() {
             ogg::PacketWriteEndInfo::EndStream
         } else {
             ogg::PacketWriteEndInfo::NormalPacket
@@ -286,7 +574,9 @@ fn write_opus_packet_to_ogg(
 fn write_header(
     writer: &mut ogg::PacketWriter,
     channels: usize,
+    sample_rate: usize,
     lookahead: usize,
+    channel_mapping: &ChannelMapping,
 ) -> anyhow::Result<()> {
     // https://wiki.xiph.org/OggOpus#ID_Header
     //  0                   1                   2                   3
@@ -306,22 +596,41 @@ fn write_header(
     // :          optional channel mapping table...                    :
     // |                                                               |
     // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-    let mut head = Vec::with_capacity(19);
+    let mut head = Vec::with_capacity(21 + channel_mapping.mapping.len());
     head.extend("OpusHead".bytes());
     head.push(1);
     head.push(channels as u8);
     head.extend((lookahead as u16).to_le_bytes());
-    head.extend(48000u32.to_le_bytes());
-    head.extend(0u16.to_le_bytes()); // Output gain
-    head.push(0);
+    head.extend((sample_rate as u32).to_le_bytes());
+    head.extend(channel_mapping.output_gain.to_le_bytes());
+    head.push(channel_mapping.family);
+
+    if channel_mapping.family != 0 {
+        // https://wiki.xiph.org/OggOpus#Channel_Mapping_Table
+        // Family 3 carries the demixing matrix instead of the mapping, RFC 8486 section 3.1
+        head.push(channel_mapping.streams);
+        head.push(channel_mapping.coupled_streams);
+        head.extend(&channel_mapping.mapping);
+    }
 
-    assert_eq!(head.len(), 19);
+    assert_eq!(
+        head.len(),
+        if channel_mapping.family == 0 {
+            19
+        } else {
+            21 + channel_mapping.mapping.len()
+        }
+    );
 
     writer.write_packet(head, SERIAL, ogg::PacketWriteEndInfo::EndPage, 0)?;
     Ok(())
 }
 
-fn write_tags(writer: &mut ogg::PacketWriter) -> anyhow::Result<()> {
+fn write_tags(
+    writer: &mut ogg::PacketWriter,
+    serial: u32,
+    comments: &[String],
+) -> anyhow::Result<()> {
     let mut opus_tags: Vec<u8> = Vec::with_capacity(60);
     opus_tags.extend(b"OpusTags");
 
@@ -329,8 +638,101 @@ fn write_tags(writer: &mut ogg::PacketWriter) -> anyhow::Result<()> {
     opus_tags.extend(&(vendor_str.len() as u32).to_le_bytes());
     opus_tags.extend(vendor_str.bytes());
 
-    opus_tags.extend(&[0u8; 4]); // No user comments
+    opus_tags.extend(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        opus_tags.extend(&(comment.len() as u32).to_le_bytes());
+        opus_tags.extend(comment.bytes());
+    }
 
-    writer.write_packet(opus_tags, SERIAL, ogg::PacketWriteEndInfo::EndPage, 0)?;
+    writer.write_packet(opus_tags, serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+    use ogg_reader::PacketReader;
+
+    const FRAME_DURATIONS: [FrameDuration; 9] = [
+        FrameDuration::Ms2_5,
+        FrameDuration::Ms5,
+        FrameDuration::Ms10,
+        FrameDuration::Ms20,
+        FrameDuration::Ms40,
+        FrameDuration::Ms60,
+        FrameDuration::Ms80,
+        FrameDuration::Ms100,
+        FrameDuration::Ms120,
+    ];
+
+    #[test]
+    fn granule_positions() {
+        let sample_rate = 16000;
+        let frames = sample_rate * 3 / 2 + 123;
+        // Samples per channel at 48 kHz
+        let to_48k = |samples: usize| samples * OUT_SAMPLE_RATE / sample_rate;
+        let lookahead = OpusEncoderWrapper::new(1, sample_rate, ChannelLayout::Speakers)
+            .unwrap()
+            .lookahead()
+            .unwrap();
+
+        for frame_duration in FRAME_DURATIONS {
+            for encoding_mode in [EncodingMode::Parallel, EncodingMode::Serial] {
+                let case = format!("{frame_duration:?} {encoding_mode:?}");
+                let pcm = (0..frames).map(|i| 0.5 * (i as f32 * 0.1).sin()).collect();
+                let options = Options {
+                    encoding_mode,
+                    // Several jobs even with the longest frames
+                    chunking: Some(Chunking {
+                        padding_frames: 4,
+                        middle_frames: 5,
+                        quiet_padding_frames: 4,
+                    }),
+                    encoder_settings: EncoderSettings {
+                        frame_duration,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let chunk = DecodedChunk {
+                    pcm,
+                    channels: 1,
+                    sample_rate,
+                };
+                let output = crate::opusify_pcm(std::iter::once(chunk), options)
+                    .unwrap()
+                    .output;
+
+                let mut reader = PacketReader::new();
+                reader.push(&output).unwrap();
+                let head = reader.next_packet().unwrap();
+                let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize;
+                assert_eq!(pre_skip, to_48k(lookahead), "{case}");
+                reader.next_packet().unwrap();
+
+                let frame_size = to_48k(frame_duration.frame_size(sample_rate));
+                let mut samples = 0;
+                let mut last = None;
+                while let Some(packet) = reader.next_packet() {
+                    let packet_samples = packet_samples(&packet.data).unwrap();
+                    assert_eq!(packet_samples, frame_size, "{case}");
+                    samples += packet_samples;
+                    if packet.end_of_stream {
+                        last = packet.granule_position;
+                    } else if let Some(granule_position) = packet.granule_position {
+                        assert_eq!(granule_position as usize, samples, "{case}");
+                    }
+                }
+
+                let end = pre_skip + to_48k(frames);
+                assert_eq!(last, Some(end as u64), "{case}");
+                // Whole frames, up to the first one that covers the end
+                assert_eq!(samples, end.next_multiple_of(frame_size), "{case}");
+
+                let decoded = decode_ogg_opus(&output, sample_rate).unwrap();
+                assert_eq!(decoded.pcm.len(), frames, "{case}");
+            }
+        }
+    }
+}
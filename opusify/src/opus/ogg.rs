@@ -283,7 +283,7 @@ const fn lookup_array() -> [u32; 0x100] {
     lup_arr
 }
 
-fn vorbis_crc32_update(cur: u32, array: &[u8]) -> u32 {
+pub(super) fn vorbis_crc32_update(cur: u32, array: &[u8]) -> u32 {
     let mut ret: u32 = cur;
     for av in array {
         ret = (ret << 8) ^ CRC_LOOKUP_ARRAY[(*av as u32 ^ (ret >> 24)) as usize];
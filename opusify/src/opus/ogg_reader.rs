@@ -0,0 +1,268 @@
+//! Reading side of [`super::ogg::PacketWriter`].
+//! https://xiph.org/ogg/doc/framing.html
+
+use super::ogg::vorbis_crc32_update;
+use std::collections::{HashMap, VecDeque};
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+const PAGE_HEADER_LEN: usize = 27;
+/// Where the checksum is in the page header
+const CRC_RANGE: std::ops::Range<usize> = 22..26;
+
+/// Takes the stream in arbitrary pieces and hands out whole packets.
+/// Logical streams are told apart by their serial.
+pub struct PacketReader {
+    /// Input not parsed yet, starting at a page boundary
+    buffer: Vec<u8>,
+    /// Offset of `buffer[0]` in the whole stream
+    buffer_offset: u64,
+    streams: HashMap<u32, StreamState>,
+    packets: VecDeque<Packet>,
+}
+
+pub struct Packet {
+    pub data: Vec<u8>,
+    pub serial: u32,
+    /// Of the page, only on the last packet that ends on it
+    pub granule_position: Option<u64>,
+    /// Last packet of its logical stream
+    pub end_of_stream: bool,
+}
+
+#[derive(Default)]
+struct StreamState {
+    /// Start of a packet continued on the next page
+    continued: Vec<u8>,
+    next_sequence_number: u32,
+    /// Of the last page that ended a packet
+    granule_position: Option<u64>,
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            buffer_offset: 0,
+            streams: HashMap::new(),
+            packets: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Result<(), crate::Error> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut offset = 0;
+        while let Some(page_len) = self.read_page(offset)? {
+            offset += page_len;
+        }
+        self.buffer.drain(..offset);
+        self.buffer_offset += offset as u64;
+
+        Ok(())
+    }
+
+    pub fn next_packet(&mut self) -> Option<Packet> {
+        self.packets.pop_front()
+    }
+
+    /// `None` if the page at `offset` isn't complete yet.
+    fn read_page(&mut self, offset: usize) -> Result<Option<usize>, crate::Error> {
+        let page_offset = self.buffer_offset + offset as u64;
+        let corrupt = |reason| crate::Error::OggRead {
+            reason,
+            offset: page_offset,
+        };
+
+        let page = &self.buffer[offset..];
+        if page.len() < PAGE_HEADER_LEN {
+            return Ok(None);
+        }
+        if &page[..4] != CAPTURE_PATTERN {
+            return Err(corrupt("missing capture pattern"));
+        }
+        if page[4] != 0 {
+            return Err(corrupt("unsupported stream structure version"));
+        }
+
+        let header_type = page[5];
+        let granule_position = u64::from_le_bytes(page[6..14].try_into().unwrap());
+        let serial = u32::from_le_bytes(page[14..18].try_into().unwrap());
+        let sequence_number = u32::from_le_bytes(page[18..22].try_into().unwrap());
+        let crc = u32::from_le_bytes(page[CRC_RANGE].try_into().unwrap());
+        let segment_count = page[26] as usize;
+
+        let header_len = PAGE_HEADER_LEN + segment_count;
+        if page.len() < header_len {
+            return Ok(None);
+        }
+        let lacing = &page[PAGE_HEADER_LEN..header_len];
+        let page_len = header_len + lacing.iter().map(|&len| len as usize).sum::<usize>();
+        if page.len() < page_len {
+            return Ok(None);
+        }
+        let page = &page[..page_len];
+
+        // Computed with the checksum field zeroed
+        let computed_crc = vorbis_crc32_update(0, &page[..CRC_RANGE.start]);
+        let computed_crc = vorbis_crc32_update(computed_crc, &[0; 4]);
+        let computed_crc = vorbis_crc32_update(computed_crc, &page[CRC_RANGE.end..]);
+        if computed_crc != crc {
+            return Err(corrupt("checksum mismatch"));
+        }
+
+        let is_continued = header_type & 0x01 != 0;
+        let is_first = header_type & 0x02 != 0;
+        let is_end_of_stream = header_type & 0x04 != 0;
+
+        let stream = self.streams.entry(serial).or_default();
+        if !is_first && sequence_number != stream.next_sequence_number {
+            return Err(corrupt("page sequence number out of order"));
+        }
+        stream.next_sequence_number = sequence_number.wrapping_add(1);
+        if is_continued == stream.continued.is_empty() {
+            return Err(corrupt(
+                "continued packet flag doesn't match the previous page",
+            ));
+        }
+
+        let mut data = &page[header_len..];
+        let mut packet = std::mem::take(&mut stream.continued);
+        let mut ended_packets = Vec::new();
+        for &len in lacing {
+            packet.extend_from_slice(&data[..len as usize]);
+            data = &data[len as usize..];
+            // A lacing value below 255 ends the packet
+            if len < 255 {
+                ended_packets.push(std::mem::take(&mut packet));
+            }
+        }
+        stream.continued = packet;
+
+        if !ended_packets.is_empty() {
+            if stream.granule_position > Some(granule_position) {
+                return Err(corrupt("granule position went backwards"));
+            }
+            stream.granule_position = Some(granule_position);
+        }
+
+        let ended_count = ended_packets.len();
+        for (index, data) in ended_packets.into_iter().enumerate() {
+            let is_last = index + 1 == ended_count;
+            self.packets.push_back(Packet {
+                data,
+                serial,
+                granule_position: is_last.then_some(granule_position),
+                end_of_stream: is_last && is_end_of_stream,
+            });
+        }
+
+        Ok(Some(page_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opus::ogg::{PacketWriteEndInfo, PacketWriter};
+    use std::sync::mpsc;
+
+    const SERIAL: u32 = 7;
+
+    /// Packets of `sizes` bytes filled with their index, one page each.
+    fn stream(sizes: &[usize]) -> Vec<u8> {
+        let (out_tx, out_rx) = mpsc::channel();
+        let mut writer = PacketWriter::new(out_tx);
+        for (index, &size) in sizes.iter().enumerate() {
+            let end_info = if index + 1 == sizes.len() {
+                PacketWriteEndInfo::EndStream
+            } else {
+                PacketWriteEndInfo::EndPage
+            };
+            writer
+                .write_packet(vec![index as u8; size], SERIAL, end_info, index as u64)
+                .unwrap();
+        }
+        drop(writer);
+        out_rx.into_iter().flatten().collect()
+    }
+
+    fn page_offsets(stream: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        while offset < stream.len() {
+            offsets.push(offset);
+            let segment_count = stream[offset + 26] as usize;
+            let lacing = &stream[offset + PAGE_HEADER_LEN..][..segment_count];
+            offset += PAGE_HEADER_LEN
+                + segment_count
+                + lacing.iter().map(|&len| len as usize).sum::<usize>();
+        }
+        offsets
+    }
+
+    /// Pushed in small pieces, so pages are split between pushes.
+    fn read(stream: &[u8]) -> Result<Vec<Packet>, crate::Error> {
+        let mut reader = PacketReader::new();
+        for piece in stream.chunks(100) {
+            reader.push(piece)?;
+        }
+        Ok(std::iter::from_fn(|| reader.next_packet()).collect())
+    }
+
+    fn read_error(stream: &[u8]) -> (&'static str, u64) {
+        match read(stream) {
+            Err(crate::Error::OggRead { reason, offset }) => (reason, offset),
+            Err(error) => panic!("{error}"),
+            Ok(_) => panic!("read succeeded"),
+        }
+    }
+
+    #[test]
+    fn packets_across_pages() {
+        // Lacing values of 255 within a page, a packet ending in a 0 lacing value,
+        // and one continued over three pages
+        let sizes = [10, 600, 255, 255 * 255 * 2 + 1, 0];
+        let packets = read(&stream(&sizes)).unwrap();
+
+        assert_eq!(packets.len(), sizes.len());
+        for (index, (packet, &size)) in packets.iter().zip(&sizes).enumerate() {
+            assert!(packet.data == vec![index as u8; size], "packet {index}");
+            assert_eq!(packet.serial, SERIAL);
+            assert_eq!(packet.granule_position, Some(index as u64));
+            assert_eq!(packet.end_of_stream, index + 1 == sizes.len());
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut stream = stream(&[10, 20, 30]);
+        let offset = page_offsets(&stream)[1];
+        stream[offset + PAGE_HEADER_LEN + 5] ^= 0x10;
+
+        assert_eq!(read_error(&stream), ("checksum mismatch", offset as u64));
+    }
+
+    #[test]
+    fn missing_capture_pattern() {
+        let mut stream = stream(&[10, 20, 30]);
+        let offset = page_offsets(&stream)[2];
+        stream[offset] = b'X';
+
+        assert_eq!(
+            read_error(&stream),
+            ("missing capture pattern", offset as u64)
+        );
+    }
+
+    #[test]
+    fn sequence_number_gap() {
+        let mut stream = stream(&[10, 20, 30]);
+        let offsets = page_offsets(&stream);
+        stream.drain(offsets[1]..offsets[2]);
+
+        assert_eq!(
+            read_error(&stream),
+            ("page sequence number out of order", offsets[1] as u64)
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use super::{
+    decode::start_of_stream, ogg, ogg_reader::PacketReader, wrapper::packet_samples, write_tags,
+};
+use crate::TagEdit;
+use anyhow::bail;
+use std::sync::mpsc;
+
+/// Rewrites an Ogg Opus stream with new tags, copying the audio packets as they are.
+/// Only the first logical stream is kept.
+pub fn remux(input: &[u8], tag_edit: &TagEdit) -> anyhow::Result<Vec<u8>> {
+    let mut reader = PacketReader::new();
+    reader.push(input)?;
+
+    let (out_tx, out_rx) = mpsc::channel();
+    let mut writer = ogg::PacketWriter::new(out_tx);
+
+    let Some(head) = reader.next_packet() else {
+        bail!(crate::Error::OpusDecode {
+            reason: "missing OpusHead",
+        });
+    };
+    let serial = head.serial;
+    let mut head = head.data;
+    if !head.starts_with(b"OpusHead") || head.len() < 19 {
+        bail!(crate::Error::OpusDecode {
+            reason: "invalid OpusHead",
+        });
+    }
+    if let Some(output_gain_db) = tag_edit.output_gain_db {
+        // Q7.8 in dB
+        let output_gain = (output_gain_db * 256.0)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        head[16..18].copy_from_slice(&output_gain.to_le_bytes());
+    }
+    writer.write_packet(head, serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
+
+    // The old tags are dropped
+    let mut packets = std::iter::from_fn(|| reader.next_packet())
+        .filter(|packet| packet.serial == serial)
+        .skip(1)
+        .collect::<Vec<_>>();
+    if let Some(end) = packets.iter().position(|packet| packet.end_of_stream) {
+        packets.truncate(end + 1);
+    }
+    write_tags(&mut writer, serial, &tag_edit.comments)?;
+
+    // Pages are laid out again, so every packet needs its own granule position,
+    // counted from the start offset of the input. Where the input has one it is kept,
+    // which also keeps the end trimming.
+    let first_page_len = packets
+        .iter()
+        .position(|packet| packet.granule_position.is_some())
+        .map_or(packets.len(), |index| index + 1);
+    let mut granule_position = start_of_stream(&packets[..first_page_len])?.unwrap_or(0);
+    let packet_count = packets.len();
+    for (index, packet) in packets.into_iter().enumerate() {
+        granule_position += packet_samples(&packet.data)? as u64;
+        if let Some(page_granule_position) = packet.granule_position {
+            granule_position = page_granule_position;
+        }
+
+        // The first audio page ends where it did in the input, the start offset is
+        // derived from it. Merged into the last page it would only trim the end.
+        let end_info = if index + 1 == packet_count {
+            ogg::PacketWriteEndInfo::EndStream
+        } else if index + 1 == first_page_len {
+            ogg::PacketWriteEndInfo::EndPage
+        } else {
+            ogg::PacketWriteEndInfo::NormalPacket
+        };
+        writer.write_packet(packet.data, serial, end_info, granule_position)?;
+    }
+    drop(writer);
+
+    Ok(out_rx.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decoded_chunk::DecodedChunk, opus::ogg_reader::Packet, Options};
+    use ogg::PacketWriteEndInfo;
+
+    fn encode() -> Vec<u8> {
+        let pcm = (0..48000 * 2)
+            .map(|i| 0.3 * (i as f32 / 2.0 * 0.05).sin())
+            .collect();
+        let chunk = DecodedChunk {
+            pcm,
+            channels: 2,
+            sample_rate: 48000,
+        };
+        crate::opusify_pcm(std::iter::once(chunk), Options::default())
+            .unwrap()
+            .output
+    }
+
+    fn read_packets(input: &[u8]) -> Vec<Packet> {
+        let mut reader = PacketReader::new();
+        reader.push(input).unwrap();
+        std::iter::from_fn(|| reader.next_packet()).collect()
+    }
+
+    fn comments(tags: &[u8]) -> Vec<String> {
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes(tags[offset..offset + 4].try_into().unwrap()) as usize
+        };
+        let mut offset = 8 + 4 + read_u32(8);
+        let count = read_u32(offset);
+        offset += 4;
+        (0..count)
+            .map(|_| {
+                let len = read_u32(offset);
+                offset += 4 + len;
+                String::from_utf8(tags[offset - len..offset].to_vec()).unwrap()
+            })
+            .collect()
+    }
+
+    /// The same packets with every granule position moved by `offset`,
+    /// `packets_per_page` audio packets to a page.
+    fn with_start_offset(input: &[u8], offset: u64, packets_per_page: usize) -> Vec<u8> {
+        let packets = read_packets(input);
+        let (out_tx, out_rx) = mpsc::channel();
+        let mut writer = ogg::PacketWriter::new(out_tx);
+
+        let packet_count = packets.len();
+        let mut granule_position = offset;
+        for (index, packet) in packets.into_iter().enumerate() {
+            let (end_info, packet_granule_position) = if index < 2 {
+                (PacketWriteEndInfo::EndPage, 0)
+            } else if index + 1 == packet_count {
+                // Keeps the end trimming
+                let end = packet.granule_position.unwrap() + offset;
+                (PacketWriteEndInfo::EndStream, end)
+            } else {
+                granule_position += packet_samples(&packet.data).unwrap() as u64;
+                let end_info = if (index - 1) % packets_per_page == 0 {
+                    PacketWriteEndInfo::EndPage
+                } else {
+                    PacketWriteEndInfo::NormalPacket
+                };
+                (end_info, granule_position)
+            };
+            writer
+                .write_packet(
+                    packet.data,
+                    packet.serial,
+                    end_info,
+                    packet_granule_position,
+                )
+                .unwrap();
+        }
+        drop(writer);
+
+        out_rx.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn replaces_tags_and_gain() {
+        let input = with_start_offset(&encode(), 0, 4);
+        let first = remux(
+            &input,
+            &TagEdit {
+                comments: vec!["TITLE=first".to_string()],
+                output_gain_db: None,
+            },
+        )
+        .unwrap();
+        let output = remux(
+            &first,
+            &TagEdit {
+                comments: vec!["TITLE=second".to_string(), "ARTIST=someone".to_string()],
+                output_gain_db: Some(-6.0),
+            },
+        )
+        .unwrap();
+
+        let input_packets = read_packets(&input);
+        let output_packets = read_packets(&output);
+        assert_eq!(output_packets.len(), input_packets.len());
+
+        let (input_head, output_head) = (&input_packets[0].data, &output_packets[0].data);
+        assert_eq!(output_head[..16], input_head[..16]);
+        assert_eq!(output_head[16..18], (-6 * 256i16).to_le_bytes());
+        assert_eq!(output_head[18..], input_head[18..]);
+        assert_eq!(
+            comments(&output_packets[1].data),
+            ["TITLE=second", "ARTIST=someone"]
+        );
+
+        for (index, (input, output)) in input_packets
+            .iter()
+            .zip(&output_packets)
+            .enumerate()
+            .skip(2)
+        {
+            assert!(input.data == output.data, "packet {index}");
+        }
+        assert!(output_packets.last().unwrap().end_of_stream);
+        assert_eq!(
+            output_packets.last().unwrap().granule_position,
+            input_packets.last().unwrap().granule_position
+        );
+    }
+
+    #[test]
+    fn keeps_the_start_offset() {
+        let encoded = encode();
+        let offset = 12345;
+        let input = with_start_offset(&encoded, offset, 4);
+        let output = remux(&input, &TagEdit::default()).unwrap();
+
+        let input_packets = read_packets(&input);
+        let output_packets = read_packets(&output);
+        let last = output_packets.len() - 1;
+        let mut samples = offset;
+        for (index, packet) in output_packets.iter().enumerate().skip(2) {
+            samples += packet_samples(&packet.data).unwrap() as u64;
+            let expected = if index == last {
+                input_packets[last].granule_position
+            } else {
+                Some(samples)
+            };
+            if packet.granule_position.is_some() {
+                assert_eq!(packet.granule_position, expected, "packet {index}");
+            }
+        }
+
+        let decode = |input: &[u8]| crate::opus::decode_ogg_opus(input, 48000).unwrap().pcm;
+        let decoded = decode(&encoded);
+        assert!(decode(&input) == decoded);
+        assert!(decode(&output) == decoded);
+    }
+}
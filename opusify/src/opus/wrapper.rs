@@ -1,11 +1,71 @@
+use crate::{Bandwidth, ChannelLayout, EncoderSettings, OpusMode, OUT_SAMPLE_RATE};
 use opusic_sys::*;
 
+// Not in the public libopus headers, from opus_private.h
+const OPUS_SET_FORCE_MODE_REQUEST: i32 = 11002;
+const MODE_SILK_ONLY: i32 = 1000;
+const MODE_HYBRID: i32 = 1001;
+const MODE_CELT_ONLY: i32 = 1002;
+
 pub struct OpusEncoderWrapper {
-    ptr: *mut OpusEncoder,
+    encoder: Encoder,
+    channel_mapping: ChannelMapping,
+    sample_rate: usize,
+}
+
+enum Encoder {
+    /// Mapping family 0, mono or stereo
+    Single(*mut OpusEncoder),
+    /// Mapping family 1, 3 to 8 channels in Vorbis channel order,
+    /// or mapping family 2, ambisonics
+    Multistream(*mut OpusMSEncoder),
+    /// Mapping family 3, ambisonics with a demixing matrix
+    Projection(*mut OpusProjectionEncoder),
+}
+
+/// What goes into the channel mapping part of the OpusHead.
+/// https://wiki.xiph.org/OggOpus#Channel_Mapping
+#[derive(Clone)]
+pub struct ChannelMapping {
+    pub family: u8,
+    pub streams: u8,
+    pub coupled_streams: u8,
+    /// Empty for family 0, the demixing matrix for family 3
+    pub mapping: Vec<u8>,
+    /// Q7.8 in dB, only non-zero for family 3
+    pub output_gain: i16,
 }
 
 impl OpusEncoderWrapper {
-    pub fn new(channels: usize, sample_rate: usize) -> Result<Self, crate::Error> {
+    pub fn new(
+        channels: usize,
+        sample_rate: usize,
+        channel_layout: ChannelLayout,
+    ) -> Result<Self, crate::Error> {
+        match channel_layout {
+            ChannelLayout::Speakers => match channels {
+                1 | 2 => Self::new_single(channels, sample_rate),
+                3..=8 => Self::new_surround(channels, sample_rate, 1),
+                _ => Err(crate::Error::OpusEncode {
+                    reason: "unsupported channel count",
+                }),
+            },
+            ChannelLayout::Ambisonics | ChannelLayout::AmbisonicsProjection => {
+                if ChannelLayout::ambisonic_order(channels).is_none() {
+                    return Err(crate::Error::OpusEncode {
+                        reason: "ambisonics needs (N+1)^2 or (N+1)^2+2 channels",
+                    });
+                }
+                if channel_layout == ChannelLayout::Ambisonics {
+                    Self::new_surround(channels, sample_rate, 2)
+                } else {
+                    Self::new_projection(channels, sample_rate)
+                }
+            }
+        }
+    }
+
+    fn new_single(channels: usize, sample_rate: usize) -> Result<Self, crate::Error> {
         unsafe {
             let mut error = 0;
             let encoder_ptr = opus_encoder_create(
@@ -15,36 +75,161 @@ impl OpusEncoderWrapper {
                 &mut error,
             );
             if error != 0 {
-                return Err(crate::Error::OpusEncode {
-                    reason: std::ffi::CStr::from_ptr(opus_strerror(error))
-                        .to_str()
-                        .unwrap(),
-                });
+                return Err(opus_error(error));
             }
 
-            Ok(OpusEncoderWrapper { ptr: encoder_ptr })
+            Ok(OpusEncoderWrapper {
+                encoder: Encoder::Single(encoder_ptr),
+                sample_rate,
+                channel_mapping: ChannelMapping {
+                    family: 0,
+                    streams: 1,
+                    coupled_streams: (channels - 1) as u8,
+                    mapping: Vec::new(),
+                    output_gain: 0,
+                },
+            })
+        }
+    }
+
+    fn new_surround(
+        channels: usize,
+        sample_rate: usize,
+        mapping_family: u8,
+    ) -> Result<Self, crate::Error> {
+        unsafe {
+            let mut error = 0;
+            let mut streams = 0;
+            let mut coupled_streams = 0;
+            let mut mapping = vec![0u8; channels];
+            let encoder_ptr = opus_multistream_surround_encoder_create(
+                sample_rate as _,
+                channels as _,
+                mapping_family as _,
+                &mut streams,
+                &mut coupled_streams,
+                mapping.as_mut_ptr(),
+                OPUS_APPLICATION_AUDIO,
+                &mut error,
+            );
+            if error != 0 {
+                return Err(opus_error(error));
+            }
+
+            Ok(OpusEncoderWrapper {
+                encoder: Encoder::Multistream(encoder_ptr),
+                sample_rate,
+                channel_mapping: ChannelMapping {
+                    family: mapping_family,
+                    streams: streams as u8,
+                    coupled_streams: coupled_streams as u8,
+                    mapping,
+                    output_gain: 0,
+                },
+            })
+        }
+    }
+
+    fn new_projection(channels: usize, sample_rate: usize) -> Result<Self, crate::Error> {
+        unsafe {
+            let mut error = 0;
+            let mut streams = 0;
+            let mut coupled_streams = 0;
+            let encoder_ptr = opus_projection_ambisonics_encoder_create(
+                sample_rate as _,
+                channels as _,
+                3,
+                &mut streams,
+                &mut coupled_streams,
+                OPUS_APPLICATION_AUDIO,
+                &mut error,
+            );
+            if error != 0 {
+                return Err(opus_error(error));
+            }
+            // From here on, drop takes care of the encoder on error
+            let mut wrapper = OpusEncoderWrapper {
+                encoder: Encoder::Projection(encoder_ptr),
+                sample_rate,
+                channel_mapping: ChannelMapping {
+                    family: 3,
+                    streams: streams as u8,
+                    coupled_streams: coupled_streams as u8,
+                    mapping: Vec::new(),
+                    output_gain: 0,
+                },
+            };
+
+            let mut matrix_size: i32 = 0;
+            let error = opus_projection_encoder_ctl(
+                encoder_ptr,
+                OPUS_PROJECTION_GET_DEMIXING_MATRIX_SIZE_REQUEST,
+                &mut matrix_size,
+            );
+            if error != 0 {
+                return Err(opus_error(error));
+            }
+
+            let mut matrix = vec![0u8; matrix_size as usize];
+            let error = opus_projection_encoder_ctl(
+                encoder_ptr,
+                OPUS_PROJECTION_GET_DEMIXING_MATRIX_REQUEST,
+                matrix.as_mut_ptr(),
+                matrix_size,
+            );
+            if error != 0 {
+                return Err(opus_error(error));
+            }
+
+            let mut gain: i32 = 0;
+            let error = opus_projection_encoder_ctl(
+                encoder_ptr,
+                OPUS_PROJECTION_GET_DEMIXING_MATRIX_GAIN_REQUEST,
+                &mut gain,
+            );
+            if error != 0 {
+                return Err(opus_error(error));
+            }
+
+            wrapper.channel_mapping.mapping = matrix;
+            wrapper.channel_mapping.output_gain = gain as i16;
+
+            Ok(wrapper)
         }
     }
 
     // TODO: Directly write on &mut [u8] instead of allocating a Vec<u8>
-    pub fn encode(&mut self, pcm: &[i16], frame_size: usize) -> Result<Vec<u8>, crate::Error> {
-        let mut output_buffer: Vec<u8> = vec![0; 8192];
+    pub fn encode(&mut self, pcm: &[f32], frame_size: usize) -> Result<Vec<u8>, crate::Error> {
+        // Frames longer than 20 ms are made of up to six 20 ms frames
+        let frames_20ms = frame_size.div_ceil(self.sample_rate / 50);
+        let mut output_buffer: Vec<u8> = vec![0; 8192 * frames_20ms];
 
         let output_len = unsafe {
-            let output_len = opus_encode(
-                self.ptr,
-                pcm.as_ptr(),
-                frame_size as _,
-                output_buffer.as_mut_ptr(),
-                output_buffer.len() as _,
-            );
+            let output_len = match self.encoder {
+                Encoder::Single(ptr) => opus_encode_float(
+                    ptr,
+                    pcm.as_ptr(),
+                    frame_size as _,
+                    output_buffer.as_mut_ptr(),
+                    output_buffer.len() as _,
+                ),
+                Encoder::Multistream(ptr) => opus_multistream_encode_float(
+                    ptr,
+                    pcm.as_ptr(),
+                    frame_size as _,
+                    output_buffer.as_mut_ptr(),
+                    output_buffer.len() as _,
+                ),
+                Encoder::Projection(ptr) => opus_projection_encode_float(
+                    ptr,
+                    pcm.as_ptr(),
+                    frame_size as _,
+                    output_buffer.as_mut_ptr(),
+                    output_buffer.len() as _,
+                ),
+            };
             if output_len < 0 {
-                let error = output_len;
-                return Err(crate::Error::OpusEncode {
-                    reason: std::ffi::CStr::from_ptr(opus_strerror(error))
-                        .to_str()
-                        .unwrap(),
-                });
+                return Err(opus_error(output_len));
             }
             output_len
         } as usize;
@@ -56,27 +241,228 @@ impl OpusEncoderWrapper {
 
     pub fn lookahead(&self) -> Result<usize, crate::Error> {
         unsafe {
-            let mut lookahead = 0;
+            let mut lookahead: i32 = 0;
 
-            let error = opus_encoder_ctl(self.ptr, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead);
+            let error = match self.encoder {
+                Encoder::Single(ptr) => {
+                    opus_encoder_ctl(ptr, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead)
+                }
+                Encoder::Multistream(ptr) => {
+                    opus_multistream_encoder_ctl(ptr, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead)
+                }
+                Encoder::Projection(ptr) => {
+                    opus_projection_encoder_ctl(ptr, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead)
+                }
+            };
 
             if error != 0 {
-                return Err(crate::Error::OpusEncode {
-                    reason: std::ffi::CStr::from_ptr(opus_strerror(error))
-                        .to_str()
-                        .unwrap(),
-                });
+                return Err(opus_error(error));
             }
 
-            Ok(lookahead)
+            Ok(lookahead as usize)
         }
     }
+
+    pub fn channel_mapping(&self) -> &ChannelMapping {
+        &self.channel_mapping
+    }
+
+    pub fn apply_settings(&mut self, settings: EncoderSettings) -> Result<(), crate::Error> {
+        if let Some(mode) = settings.mode {
+            self.set(
+                OPUS_SET_FORCE_MODE_REQUEST,
+                match mode {
+                    OpusMode::SilkOnly => MODE_SILK_ONLY,
+                    OpusMode::Hybrid => MODE_HYBRID,
+                    OpusMode::CeltOnly => MODE_CELT_ONLY,
+                },
+            )?;
+        }
+        if let Some(bandwidth) = settings.bandwidth {
+            self.set(
+                OPUS_SET_BANDWIDTH_REQUEST,
+                match bandwidth {
+                    Bandwidth::Narrowband => OPUS_BANDWIDTH_NARROWBAND,
+                    Bandwidth::Mediumband => OPUS_BANDWIDTH_MEDIUMBAND,
+                    Bandwidth::Wideband => OPUS_BANDWIDTH_WIDEBAND,
+                    Bandwidth::SuperWideband => OPUS_BANDWIDTH_SUPERWIDEBAND,
+                    Bandwidth::Fullband => OPUS_BANDWIDTH_FULLBAND,
+                },
+            )?;
+        }
+        if let Some(bitrate) = settings.bitrate {
+            // libopus clamps it to what it can do
+            self.set(
+                OPUS_SET_BITRATE_REQUEST,
+                bitrate.min(i32::MAX as u32) as i32,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Multistream and projection encoders pass it on to every stream.
+    fn set(&mut self, request: i32, value: i32) -> Result<(), crate::Error> {
+        let error = unsafe {
+            match self.encoder {
+                Encoder::Single(ptr) => opus_encoder_ctl(ptr, request, value),
+                Encoder::Multistream(ptr) => opus_multistream_encoder_ctl(ptr, request, value),
+                Encoder::Projection(ptr) => opus_projection_encoder_ctl(ptr, request, value),
+            }
+        };
+        if error != 0 {
+            return Err(opus_error(error));
+        }
+        Ok(())
+    }
 }
 
 impl Drop for OpusEncoderWrapper {
     fn drop(&mut self) {
         unsafe {
-            opus_encoder_destroy(self.ptr);
+            match self.encoder {
+                Encoder::Single(ptr) => opus_encoder_destroy(ptr),
+                Encoder::Multistream(ptr) => opus_multistream_encoder_destroy(ptr),
+                Encoder::Projection(ptr) => opus_projection_encoder_destroy(ptr),
+            }
         }
     }
 }
+
+pub struct OpusDecoderWrapper {
+    decoder: Decoder,
+    channels: usize,
+}
+
+enum Decoder {
+    /// Mapping families 0, 1 and 2. Family 0 is a single stream.
+    Multistream(*mut OpusMSDecoder),
+    /// Mapping family 3
+    Projection(*mut OpusProjectionDecoder),
+}
+
+/// 120 ms, the longest Opus packet
+const MAX_FRAME_SIZE: usize = 5760;
+
+impl OpusDecoderWrapper {
+    /// `sample_rate` is the output rate, one of the Opus sample rates.
+    pub fn new(
+        channels: usize,
+        sample_rate: usize,
+        channel_mapping: &ChannelMapping,
+    ) -> Result<Self, crate::Error> {
+        unsafe {
+            let mut error = 0;
+            let decoder = match channel_mapping.family {
+                0 => {
+                    let mapping = [0, 1];
+                    Decoder::Multistream(opus_multistream_decoder_create(
+                        sample_rate as _,
+                        channels as _,
+                        1,
+                        (channels - 1) as _,
+                        mapping.as_ptr(),
+                        &mut error,
+                    ))
+                }
+                3 => {
+                    let mut matrix = channel_mapping.mapping.clone();
+                    Decoder::Projection(opus_projection_decoder_create(
+                        sample_rate as _,
+                        channels as _,
+                        channel_mapping.streams as _,
+                        channel_mapping.coupled_streams as _,
+                        matrix.as_mut_ptr(),
+                        matrix.len() as _,
+                        &mut error,
+                    ))
+                }
+                _ => Decoder::Multistream(opus_multistream_decoder_create(
+                    sample_rate as _,
+                    channels as _,
+                    channel_mapping.streams as _,
+                    channel_mapping.coupled_streams as _,
+                    channel_mapping.mapping.as_ptr(),
+                    &mut error,
+                )),
+            };
+            if error != 0 {
+                return Err(opus_decode_error(error));
+            }
+
+            Ok(OpusDecoderWrapper { decoder, channels })
+        }
+    }
+
+    /// Interleaved output of one packet.
+    pub fn decode(&mut self, packet: &[u8]) -> Result<Vec<f32>, crate::Error> {
+        let mut pcm = vec![0f32; MAX_FRAME_SIZE * self.channels];
+
+        let frame_size = unsafe {
+            let frame_size = match self.decoder {
+                Decoder::Multistream(ptr) => opus_multistream_decode_float(
+                    ptr,
+                    packet.as_ptr(),
+                    packet.len() as _,
+                    pcm.as_mut_ptr(),
+                    MAX_FRAME_SIZE as _,
+                    0,
+                ),
+                Decoder::Projection(ptr) => opus_projection_decode_float(
+                    ptr,
+                    packet.as_ptr(),
+                    packet.len() as _,
+                    pcm.as_mut_ptr(),
+                    MAX_FRAME_SIZE as _,
+                    0,
+                ),
+            };
+            if frame_size < 0 {
+                return Err(opus_decode_error(frame_size));
+            }
+            frame_size
+        } as usize;
+
+        pcm.truncate(frame_size * self.channels);
+
+        Ok(pcm)
+    }
+}
+
+impl Drop for OpusDecoderWrapper {
+    fn drop(&mut self) {
+        unsafe {
+            match self.decoder {
+                Decoder::Multistream(ptr) => opus_multistream_decoder_destroy(ptr),
+                Decoder::Projection(ptr) => opus_projection_decoder_destroy(ptr),
+            }
+        }
+    }
+}
+
+/// Duration of a packet at 48 kHz, without decoding it.
+/// For multistream packets, the first stream is as long as the others.
+pub fn packet_samples(packet: &[u8]) -> Result<usize, crate::Error> {
+    let samples = unsafe {
+        opus_packet_get_nb_samples(packet.as_ptr(), packet.len() as _, OUT_SAMPLE_RATE as _)
+    };
+    if samples < 0 {
+        return Err(opus_decode_error(samples));
+    }
+    Ok(samples as usize)
+}
+
+fn opus_error(error: i32) -> crate::Error {
+    crate::Error::OpusEncode {
+        reason: unsafe { std::ffi::CStr::from_ptr(opus_strerror(error)) }
+            .to_str()
+            .unwrap(),
+    }
+}
+
+fn opus_decode_error(error: i32) -> crate::Error {
+    crate::Error::OpusDecode {
+        reason: unsafe { std::ffi::CStr::from_ptr(opus_strerror(error)) }
+            .to_str()
+            .unwrap(),
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{
+    decoded_chunk::{self, DecodedChunk},
+    opus::{self, Chunking},
+    ChannelLayout, FrameDuration,
+};
+use anyhow::bail;
+use realfft::RealFftPlanner;
+use std::sync::{mpsc, Arc, Mutex};
+
+/// 20 ms, the default Opus frame duration
+pub const ANALYSIS_FRAME_SECONDS: f64 = 0.02;
+/// Frames quieter than this in the source are left out of the spectral distortion, about -60 dBFS
+const SILENT_FRAME_POWER: f32 = 1e-6;
+/// Keeps the log spectra finite for empty bins
+const POWER_FLOOR: f32 = 1e-10;
+
+#[derive(Debug, Clone, Default)]
+pub struct QualityReport {
+    /// Over the whole output, in dB
+    pub snr_db: f64,
+    /// Log-spectral distance averaged over 20 ms frames with signal, in dB
+    pub spectral_distortion_db: f64,
+    /// One per encoding job, in order
+    pub chunks: Vec<ChunkQuality>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkQuality {
+    /// First frame of the job in the output
+    pub start_frame: usize,
+    /// Over the whole job, in dB
+    pub snr_db: f64,
+    /// Over the 20 ms on either side of `start_frame`, in dB.
+    /// Well below `snr_db` means the handover to a fresh encoder is audible.
+    pub boundary_snr_db: f64,
+}
+
+/// Passes the chunks through and keeps a copy of them, complete once the output is.
+pub fn record(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+) -> (
+    mpsc::Receiver<DecodedChunk>,
+    Arc<Mutex<Option<DecodedChunk>>>,
+) {
+    let (out_tx, out_rx) = mpsc::channel();
+    let recorded = Arc::new(Mutex::new(None));
+
+    std::thread::spawn({
+        let recorded = recorded.clone();
+        move || {
+            let mut source: Option<DecodedChunk> = None;
+
+            while let Ok(chunk) = in_rx.recv() {
+                match source.as_mut() {
+                    Some(source) => source.pcm.extend_from_slice(&chunk.pcm),
+                    None => {
+                        source = Some(DecodedChunk {
+                            pcm: chunk.pcm.clone(),
+                            channels: chunk.channels,
+                            sample_rate: chunk.sample_rate,
+                        })
+                    }
+                }
+                if out_tx.send(chunk).is_err() {
+                    break;
+                }
+            }
+
+            decoded_chunk::close_with(out_tx, &recorded, |recorded| *recorded = source);
+        }
+    });
+
+    (out_rx, recorded)
+}
+
+/// Compares `encoded` with `source`, the PCM that went into the encoder.
+/// `source` has to be at one of the Opus sample rates, and `channel_layout`,
+/// `frame_duration` and `chunking` the ones used for encoding, to find the job boundaries.
+/// `chunking` is `None` for [`crate::EncodingMode::Serial`].
+pub fn analyze(
+    source: &DecodedChunk,
+    encoded: &[u8],
+    channel_layout: ChannelLayout,
+    frame_duration: FrameDuration,
+    chunking: Option<Chunking>,
+) -> anyhow::Result<QualityReport> {
+    // The decoder drops the pre-skip, so both start at the same sample
+    let decoded = opus::decode_ogg_opus(encoded, source.sample_rate)?;
+    if decoded.channels != source.channels {
+        bail!("decoded channel count differs from the source");
+    }
+
+    let channels = source.channels;
+    let frames = source.pcm.len().min(decoded.pcm.len()) / channels;
+    let source_pcm = &source.pcm[..frames * channels];
+    let decoded_pcm = &decoded.pcm[..frames * channels];
+
+    let snr_of_frames = |range: std::ops::Range<usize>| {
+        let range = range.start.min(frames) * channels..range.end.min(frames) * channels;
+        snr_db(&source_pcm[range.clone()], &decoded_pcm[range])
+    };
+
+    let window_frames = (ANALYSIS_FRAME_SECONDS * source.sample_rate as f64) as usize;
+    let mut job_starts = vec![0];
+    if let Some(chunking) = chunking {
+        job_starts.extend(opus::job_boundaries(
+            frames,
+            channels,
+            source.sample_rate,
+            channel_layout,
+            frame_duration,
+            chunking,
+        )?);
+    }
+    let chunks = job_starts
+        .iter()
+        .enumerate()
+        .map(|(index, &start_frame)| {
+            let end_frame = job_starts.get(index + 1).copied().unwrap_or(frames);
+            ChunkQuality {
+                start_frame,
+                snr_db: snr_of_frames(start_frame..end_frame),
+                boundary_snr_db: snr_of_frames(
+                    start_frame.saturating_sub(window_frames)..start_frame + window_frames,
+                ),
+            }
+        })
+        .collect();
+
+    Ok(QualityReport {
+        snr_db: snr_of_frames(0..frames),
+        spectral_distortion_db: spectral_distortion_db(
+            source_pcm,
+            decoded_pcm,
+            channels,
+            window_frames,
+        ),
+        chunks,
+    })
+}
+
+/// Infinite if both are identical.
+pub fn snr_db(source: &[f32], decoded: &[f32]) -> f64 {
+    let signal = source.iter().map(|&s| (s as f64).powi(2)).sum::<f64>();
+    let noise = source
+        .iter()
+        .zip(decoded)
+        .map(|(&s, &d)| (s as f64 - d as f64).powi(2))
+        .sum::<f64>();
+    10.0 * (signal / noise).log10()
+}
+
+/// Root mean square difference of the Hann windowed log power spectra, averaged over
+/// every channel and every frame that isn't silent in the source.
+fn spectral_distortion_db(
+    source: &[f32],
+    decoded: &[f32],
+    channels: usize,
+    window_frames: usize,
+) -> f64 {
+    let fft = RealFftPlanner::<f32>::new().plan_fft_forward(window_frames);
+    let window = (0..window_frames)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / window_frames as f32).cos())
+        .collect::<Vec<_>>();
+
+    let mut input = fft.make_input_vec();
+    let mut source_spectrum = fft.make_output_vec();
+    let mut decoded_spectrum = fft.make_output_vec();
+    let mut spectrum = |pcm: &[f32], channel: usize, output: &mut Vec<_>| {
+        input
+            .iter_mut()
+            .zip(pcm.iter().skip(channel).step_by(channels))
+            .zip(&window)
+            .for_each(|((input, &sample), &window)| *input = sample * window);
+        fft.process(&mut input, output).unwrap();
+    };
+
+    let mut distortion_sum = 0.0;
+    let mut measured = 0;
+    let window_len = window_frames * channels;
+    for (source_window, decoded_window) in source
+        .chunks_exact(window_len)
+        .zip(decoded.chunks_exact(window_len))
+    {
+        for channel in 0..channels {
+            let power = source_window
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .map(|s| s * s)
+                .sum::<f32>()
+                / window_frames as f32;
+            if power < SILENT_FRAME_POWER {
+                continue;
+            }
+
+            spectrum(source_window, channel, &mut source_spectrum);
+            spectrum(decoded_window, channel, &mut decoded_spectrum);
+
+            let squared_sum = source_spectrum
+                .iter()
+                .zip(&decoded_spectrum)
+                .map(|(s, d)| {
+                    let s = s.norm_sqr().max(POWER_FLOOR);
+                    let d = d.norm_sqr().max(POWER_FLOOR);
+                    (10.0 * (s / d).log10() as f64).powi(2)
+                })
+                .sum::<f64>();
+            distortion_sum += (squared_sum / source_spectrum.len() as f64).sqrt();
+            measured += 1;
+        }
+    }
+
+    if measured == 0 {
+        return 0.0;
+    }
+    distortion_sum / measured as f64
+}
@@ -0,0 +1,107 @@
+use crate::{
+    decoded_chunk::{self, DecodedChunk},
+    ConversionStats, Quantization,
+};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Above this, the soft limiter starts to compress. About -0.9 dBFS.
+const SOFT_LIMIT_THRESHOLD: f32 = 0.9;
+
+/// Rounds every sample to the 16-bit grid, so the encoder sees exactly what a 16-bit
+/// pipeline would have produced. Samples stay f32, scaled back to -1.0..=1.0.
+pub fn quantize(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+    quantization: Quantization,
+    stats: Arc<Mutex<ConversionStats>>,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let now = std::time::Instant::now();
+        let mut quantizer = Quantizer::new(quantization);
+
+        while let Ok(mut chunk) = in_rx.recv() {
+            chunk.pcm.iter_mut().for_each(|sample| {
+                *sample = quantizer.quantize(*sample) as f32 / 32768.0;
+            });
+            if out_tx.send(chunk).is_err() {
+                break;
+            }
+        }
+
+        println!(
+            "quantize thread finished, clipped {} samples, elapsed: {:?}",
+            quantizer.clipped_samples,
+            now.elapsed()
+        );
+
+        decoded_chunk::close_with(out_tx, &stats, |stats| {
+            stats.clipped_samples += quantizer.clipped_samples;
+        });
+    });
+
+    out_rx
+}
+
+pub struct Quantizer {
+    quantization: Quantization,
+    /// xorshift32, seeded with a constant so the output is reproducible
+    rng_state: u32,
+    pub clipped_samples: usize,
+}
+
+impl Quantizer {
+    pub fn new(quantization: Quantization) -> Self {
+        Self {
+            quantization,
+            rng_state: 0x9E37_79B9,
+            clipped_samples: 0,
+        }
+    }
+
+    pub fn quantize(&mut self, sample: f32) -> i16 {
+        let mut sample = sample;
+
+        if self.quantization.soft_limit {
+            sample = soft_limit(sample);
+        }
+
+        let mut scaled = sample * 32768.0;
+
+        if self.quantization.dither {
+            // TPDF, the sum of two uniform distributions of 1 LSB each
+            scaled += self.next_uniform() + self.next_uniform();
+        }
+
+        let rounded = scaled.round();
+
+        if !(i16::MIN as f32..=i16::MAX as f32).contains(&rounded) {
+            self.clipped_samples += 1;
+        }
+
+        rounded.clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+
+    /// -0.5..0.5
+    fn next_uniform(&mut self) -> f32 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 17;
+        self.rng_state ^= self.rng_state << 5;
+        (self.rng_state as f64 / u32::MAX as f64 - 0.5) as f32
+    }
+}
+
+/// Linear up to the threshold, then a tanh knee that approaches but never reaches full scale.
+fn soft_limit(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= SOFT_LIMIT_THRESHOLD {
+        return sample;
+    }
+
+    let ceiling = i16::MAX as f32 / 32768.0;
+    let headroom = ceiling - SOFT_LIMIT_THRESHOLD;
+    let limited =
+        SOFT_LIMIT_THRESHOLD + headroom * ((magnitude - SOFT_LIMIT_THRESHOLD) / headroom).tanh();
+
+    limited.copysign(sample)
+}
@@ -0,0 +1,169 @@
+use crate::{decoded_chunk::DecodedChunk, Decoder, Endianness, RawPcmFormat, SampleFormat};
+use anyhow::bail;
+use std::sync::mpsc;
+
+/// Headerless PCM in a format declared by the caller, so there is nothing to probe.
+pub struct RawPcmDecoder {
+    format: RawPcmFormat,
+}
+
+impl RawPcmDecoder {
+    pub fn new(format: RawPcmFormat) -> Self {
+        Self { format }
+    }
+}
+
+impl Decoder for RawPcmDecoder {
+    fn probe(&self, _head: &[u8]) -> bool {
+        true
+    }
+
+    fn decode(
+        &mut self,
+        in_rx: mpsc::Receiver<bytes::Bytes>,
+        out_tx: mpsc::Sender<DecodedChunk>,
+    ) -> anyhow::Result<()> {
+        let RawPcmFormat {
+            sample_format,
+            endianness,
+            sample_rate,
+            channels,
+        } = self.format;
+        if channels == 0 || sample_rate == 0 {
+            bail!("raw PCM needs at least one channel and a sample rate");
+        }
+
+        let sample_bytes = match sample_format {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::F32 => 4,
+        };
+        let frame_bytes = sample_bytes * channels;
+
+        // Bytes of a frame split between two reads
+        let mut pending = Vec::new();
+
+        while let Ok(bytes) = in_rx.recv() {
+            pending.extend_from_slice(&bytes);
+            let whole_len = pending.len() / frame_bytes * frame_bytes;
+
+            let pcm = pending[..whole_len]
+                .chunks_exact(sample_bytes)
+                .map(|sample| to_f32(sample, sample_format, endianness))
+                .collect::<Vec<_>>();
+            pending.drain(..whole_len);
+
+            if pcm.is_empty() {
+                continue;
+            }
+            if out_tx
+                .send(DecodedChunk {
+                    pcm,
+                    channels,
+                    sample_rate,
+                })
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+
+        if !pending.is_empty() {
+            bail!(
+                "raw PCM ends with a partial frame of {} bytes, the format doesn't match the input",
+                pending.len()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn to_f32(sample: &[u8], sample_format: SampleFormat, endianness: Endianness) -> f32 {
+    // As little endian
+    let mut bytes = [0u8; 4];
+    bytes[..sample.len()].copy_from_slice(sample);
+    if endianness == Endianness::Big {
+        bytes[..sample.len()].reverse();
+    }
+
+    match sample_format {
+        SampleFormat::S16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+        // Into the top of an i32 to sign extend
+        SampleFormat::S24 => {
+            (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as f32 / 8388608.0
+        }
+        SampleFormat::F32 => f32::from_le_bytes(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(input: &[u8]) -> (anyhow::Result<()>, Vec<f32>) {
+        let (in_tx, in_rx) = mpsc::channel();
+        // Split in the middle of a frame
+        for piece in input.chunks(3) {
+            in_tx.send(bytes::Bytes::copy_from_slice(piece)).unwrap();
+        }
+        drop(in_tx);
+
+        let (out_tx, out_rx) = mpsc::channel();
+        let mut decoder = RawPcmDecoder::new(RawPcmFormat {
+            sample_format: SampleFormat::S16,
+            endianness: Endianness::Little,
+            sample_rate: 48000,
+            channels: 2,
+        });
+        let result = decoder.decode(in_rx, out_tx);
+        (result, out_rx.iter().flat_map(|chunk| chunk.pcm).collect())
+    }
+
+    #[test]
+    fn whole_frames() {
+        let (result, pcm) = decode(&[0x00, 0x40, 0x00, 0xc0, 0xff, 0x7f, 0x00, 0x80]);
+        assert!(result.is_ok());
+        assert_eq!(pcm, [0.5, -0.5, 32767.0 / 32768.0, -1.0]);
+    }
+
+    #[test]
+    fn partial_frame() {
+        let (result, pcm) = decode(&[0x00, 0x40, 0x00, 0xc0, 0xff, 0x7f]);
+        assert!(result.is_err());
+        assert_eq!(pcm, [0.5, -0.5]);
+    }
+
+    #[test]
+    fn partial_frame_fails_conversion() {
+        let convert = |bytes: &[u8]| {
+            let path =
+                std::env::temp_dir().join(format!("opusify-{}-partial.raw", std::process::id()));
+            std::fs::write(&path, bytes).unwrap();
+            let options = crate::Options {
+                raw_pcm: Some(RawPcmFormat {
+                    sample_format: SampleFormat::S16,
+                    endianness: Endianness::Little,
+                    sample_rate: 48000,
+                    channels: 2,
+                }),
+                ..Default::default()
+            };
+            let result = crate::opusify_with_options(&path, options);
+            std::fs::remove_file(path).unwrap();
+            result
+        };
+        // A second of stereo
+        let mut bytes = vec![0; 48000 * 4];
+
+        assert!(convert(&bytes).is_ok());
+        bytes.extend_from_slice(&[0; 3]);
+        let Err(error) = convert(&bytes) else {
+            panic!("conversion succeeded");
+        };
+        assert!(matches!(
+            error.downcast_ref::<crate::Error>(),
+            Some(crate::Error::Decode { .. })
+        ));
+    }
+}
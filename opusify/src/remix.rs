@@ -0,0 +1,137 @@
+use crate::{decoded_chunk::DecodedChunk, ChannelConversion, MonoDownmix};
+use std::sync::mpsc;
+
+/// -3 dB, ITU-R BS.775 center and surround level
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+pub fn remix(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+    err_tx: mpsc::Sender<crate::Error>,
+    conversion: ChannelConversion,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let now = std::time::Instant::now();
+        let result: Result<(), crate::Error> = (|| {
+            while let Ok(chunk) = in_rx.recv() {
+                let chunk = convert(chunk, &conversion)?;
+                if out_tx.send(chunk).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })();
+
+        println!("remix thread finished, elapsed: {:?}", now.elapsed());
+
+        if let Err(error) = result {
+            let _ = err_tx.send(error);
+        }
+    });
+
+    out_rx
+}
+
+fn convert(
+    chunk: DecodedChunk,
+    conversion: &ChannelConversion,
+) -> Result<DecodedChunk, crate::Error> {
+    let channels = chunk.channels;
+
+    match conversion {
+        ChannelConversion::Mono(downmix) => {
+            if channels == 1 {
+                return Ok(chunk);
+            }
+            let coefficients = match downmix {
+                MonoDownmix::Average => vec![1.0 / channels as f32; channels],
+                MonoDownmix::Coefficients(coefficients) => {
+                    if coefficients.len() != channels {
+                        return Err(crate::Error::ChannelConversion {
+                            reason: "mono downmix needs one coefficient per input channel",
+                        });
+                    }
+                    coefficients.clone()
+                }
+            };
+            Ok(mix(chunk, &[coefficients.as_slice()]))
+        }
+        ChannelConversion::MonoToStereo => match channels {
+            1 => Ok(DecodedChunk {
+                pcm: chunk
+                    .pcm
+                    .iter()
+                    .flat_map(|&sample| [sample, sample])
+                    .collect(),
+                channels: 2,
+                sample_rate: chunk.sample_rate,
+            }),
+            2 => Ok(chunk),
+            _ => Err(crate::Error::ChannelConversion {
+                reason: "mono to stereo needs a mono or stereo input",
+            }),
+        },
+        ChannelConversion::LeftOnly | ChannelConversion::RightOnly => match channels {
+            1 => Ok(chunk),
+            2 => {
+                let channel_index = match conversion {
+                    ChannelConversion::LeftOnly => 0,
+                    _ => 1,
+                };
+                Ok(DecodedChunk {
+                    pcm: chunk
+                        .pcm
+                        .chunks_exact(2)
+                        .map(|frame| frame[channel_index])
+                        .collect(),
+                    channels: 1,
+                    sample_rate: chunk.sample_rate,
+                })
+            }
+            _ => Err(crate::Error::ChannelConversion {
+                reason: "channel extraction needs a stereo input",
+            }),
+        },
+        ChannelConversion::Surround51ToStereo => {
+            if channels != 6 {
+                return Err(crate::Error::ChannelConversion {
+                    reason: "5.1 downmix needs a 6 channel input",
+                });
+            }
+            // Vorbis channel order: front left, center, front right, rear left, rear right, LFE.
+            // LFE is dropped, normalized so a full scale input can't clip.
+            let normalize = 1.0 / (1.0 + 2.0 * MINUS_3DB);
+            let center = MINUS_3DB * normalize;
+            let surround = MINUS_3DB * normalize;
+            Ok(mix(
+                chunk,
+                &[
+                    &[normalize, center, 0.0, surround, 0.0, 0.0],
+                    &[0.0, center, normalize, 0.0, surround, 0.0],
+                ],
+            ))
+        }
+    }
+}
+
+/// Each output channel is a weighted sum of the input channels.
+fn mix(chunk: DecodedChunk, matrix: &[&[f32]]) -> DecodedChunk {
+    DecodedChunk {
+        pcm: chunk
+            .pcm
+            .chunks_exact(chunk.channels)
+            .flat_map(|frame| {
+                matrix.iter().map(move |coefficients| {
+                    frame
+                        .iter()
+                        .zip(coefficients.iter())
+                        .map(|(&sample, coefficient)| sample * coefficient)
+                        .sum::<f32>()
+                })
+            })
+            .collect(),
+        channels: matrix.len(),
+        sample_rate: chunk.sample_rate,
+    }
+}
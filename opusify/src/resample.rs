@@ -1,10 +1,17 @@
-use crate::{decoded_chunk::DecodedChunk, OUT_SAMPLE_RATE};
+use crate::{decoded_chunk::DecodedChunk, ResampleQuality, SincParameters, OUT_SAMPLE_RATE};
 use rubato::*;
 use std::sync::mpsc;
 
+/// Sample rates libopus can encode directly.
+const OPUS_SAMPLE_RATES: [usize; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// Input frames per channel for every resampler call, independent of the decoder's frame size.
+const BLOCK_FRAMES: usize = 1024;
+
 pub fn resample(
     in_rx: mpsc::Receiver<DecodedChunk>,
     err_tx: mpsc::Sender<crate::Error>,
+    quality: ResampleQuality,
 ) -> mpsc::Receiver<DecodedChunk> {
     let (out_tx, out_rx) = mpsc::channel();
 
@@ -15,70 +22,62 @@ pub fn resample(
             let result: anyhow::Result<()> = (|| {
                 let mut chunk = in_rx.recv()?;
 
-                let sample_rate = chunk.sample_rate;
+                // The encoder is configured from the first chunk, so keep the channel layout
+                // and sample rate even if the input changes them mid-stream.
                 let channels = chunk.channels;
+                let out_sample_rate = encoding_sample_rate(chunk.sample_rate);
+                let mut sample_rate = chunk.sample_rate;
 
-                let mut resampler = FftFixedIn::<f32>::new(
-                    sample_rate as _,
-                    OUT_SAMPLE_RATE as _,
-                    chunk.pcm.len() / channels,
-                    8,
-                    channels,
-                )?;
-
-                let mut wave_in = vec![0f32; chunk.pcm.len()];
+                let mut resampler =
+                    BlockResampler::new_if_needed(sample_rate, out_sample_rate, channels, quality)?;
 
                 loop {
-                    samples_sum += chunk.pcm.len() / channels;
-                    if channels == 1 {
-                        chunk.pcm.iter().enumerate().for_each(|(index, &sample)| {
-                            wave_in[index] = sample as f32 / i16::MAX as f32;
-                        });
+                    if chunk.channels != channels {
+                        chunk = adapt_channels(chunk, channels)?;
+                    }
 
-                        let mut resampled = Resampler::process(&mut resampler, &[&wave_in], None)?;
+                    if chunk.sample_rate != sample_rate {
+                        println!(
+                            "sample rate changed from {} to {}",
+                            sample_rate, chunk.sample_rate
+                        );
 
-                        out_tx.send(DecodedChunk {
-                            sample_rate: OUT_SAMPLE_RATE,
+                        // Flush what is left in the old resampler before replacing it
+                        if let Some(mut resampler) = resampler.take() {
+                            send_pcm(&out_tx, resampler.flush()?, channels, out_sample_rate)?;
+                        }
+                        sample_rate = chunk.sample_rate;
+                        resampler = BlockResampler::new_if_needed(
+                            sample_rate,
+                            out_sample_rate,
                             channels,
-                            pcm: resampled
-                                .remove(0)
-                                .into_iter()
-                                .map(|sample| (sample * i16::MAX as f32) as i16)
-                                .collect(),
-                        })?;
-                    } else {
-                        assert_eq!(channels, 2);
-                        let (left, right) = wave_in.split_at_mut(chunk.pcm.len() / 2);
-                        chunk
-                            .pcm
-                            .chunks_exact(2)
-                            .enumerate()
-                            .for_each(|(i, chunk)| {
-                                left[i] = chunk[0] as f32 / i16::MAX as f32;
-                                right[i] = chunk[1] as f32 / i16::MAX as f32;
-                            });
-
-                        let resampled = Resampler::process(&mut resampler, &[&left, &right], None)?;
-
-                        out_tx.send(DecodedChunk {
-                            sample_rate: OUT_SAMPLE_RATE,
+                            quality,
+                        )?;
+                    }
+
+                    samples_sum += chunk.pcm.len() / channels;
+
+                    match resampler.as_mut() {
+                        Some(resampler) => send_pcm(
+                            &out_tx,
+                            resampler.push(&chunk.pcm)?,
                             channels,
-                            // interleaved
-                            pcm: resampled[0]
-                                .iter()
-                                .zip(resampled[1].iter())
-                                .flat_map(|(left, right)| {
-                                    [
-                                        (left * i16::MAX as f32) as i16,
-                                        (right * i16::MAX as f32) as i16,
-                                    ]
-                                })
-                                .collect(),
-                        })?;
+                            out_sample_rate,
+                        )?,
+                        None => out_tx.send(chunk)?,
                     }
 
-                    chunk = in_rx.recv()?;
+                    let Ok(next_chunk) = in_rx.recv() else {
+                        break;
+                    };
+                    chunk = next_chunk;
+                }
+
+                if let Some(mut resampler) = resampler {
+                    send_pcm(&out_tx, resampler.flush()?, channels, out_sample_rate)?;
                 }
+
+                Ok(())
             })();
 
             println!(
@@ -88,8 +87,15 @@ pub fn resample(
             );
 
             if let Err(err) = result {
-                if let Ok(error) = err.downcast::<ResamplerConstructionError>() {
-                    let _ = err_tx.send(crate::Error::Resample { error });
+                match err.downcast::<ResamplerConstructionError>() {
+                    Ok(error) => {
+                        let _ = err_tx.send(crate::Error::Resample { error });
+                    }
+                    Err(err) => {
+                        if let Ok(error) = err.downcast::<crate::Error>() {
+                            let _ = err_tx.send(error);
+                        }
+                    }
                 }
             }
         }
@@ -97,3 +103,423 @@ pub fn resample(
 
     out_rx
 }
+
+/// Opus native rates are encoded as they are, everything else is resampled to 48 kHz.
+fn encoding_sample_rate(sample_rate: usize) -> usize {
+    if OPUS_SAMPLE_RATES.contains(&sample_rate) {
+        sample_rate
+    } else {
+        OUT_SAMPLE_RATE
+    }
+}
+
+fn send_pcm(
+    out_tx: &mpsc::Sender<DecodedChunk>,
+    pcm: Vec<f32>,
+    channels: usize,
+    sample_rate: usize,
+) -> anyhow::Result<()> {
+    if pcm.is_empty() {
+        return Ok(());
+    }
+    out_tx.send(DecodedChunk {
+        pcm,
+        channels,
+        sample_rate,
+    })?;
+    Ok(())
+}
+
+/// Feeds arbitrarily sized input to the resampler in the block size it requires,
+/// and drops the resampler delay so the output lines up with the input.
+struct BlockResampler {
+    resampler: Box<dyn VecResampler<f32>>,
+    sample_rate: usize,
+    out_sample_rate: usize,
+    channels: usize,
+    /// Deinterleaved input waiting for a full block
+    pending: Vec<Vec<f32>>,
+    /// Output frames still to be dropped at the start
+    delay_frames: usize,
+    input_frames: usize,
+    output_frames: usize,
+}
+
+impl BlockResampler {
+    /// `None` if the input can be passed through as it is.
+    fn new_if_needed(
+        sample_rate: usize,
+        out_sample_rate: usize,
+        channels: usize,
+        quality: ResampleQuality,
+    ) -> Result<Option<Self>, ResamplerConstructionError> {
+        if sample_rate == out_sample_rate {
+            return Ok(None);
+        }
+
+        let ratio = out_sample_rate as f64 / sample_rate as f64;
+        let resampler: Box<dyn VecResampler<f32>> = match quality {
+            ResampleQuality::Fast => Box::new(FastFixedIn::<f32>::new(
+                ratio,
+                1.0,
+                PolynomialDegree::Linear,
+                BLOCK_FRAMES,
+                channels,
+            )?),
+            ResampleQuality::Balanced => Box::new(FftFixedIn::<f32>::new(
+                sample_rate,
+                out_sample_rate,
+                BLOCK_FRAMES,
+                8,
+                channels,
+            )?),
+            ResampleQuality::Best => Box::new(SincFixedIn::<f32>::new(
+                ratio,
+                1.0,
+                sinc_interpolation_parameters(SincParameters {
+                    sinc_len: 256,
+                    f_cutoff: calculate_cutoff(256, WindowFunction::BlackmanHarris2),
+                    oversampling_factor: 256,
+                }),
+                BLOCK_FRAMES,
+                channels,
+            )?),
+            ResampleQuality::Sinc(parameters) => Box::new(SincFixedIn::<f32>::new(
+                ratio,
+                1.0,
+                sinc_interpolation_parameters(parameters),
+                BLOCK_FRAMES,
+                channels,
+            )?),
+        };
+        let delay_frames = match quality {
+            ResampleQuality::Fast | ResampleQuality::Balanced => resampler.output_delay(),
+            // SincFixedIn starts half a window before the first input frame,
+            // so its output is not delayed even though output_delay() says it is.
+            ResampleQuality::Best | ResampleQuality::Sinc(_) => 0,
+        };
+
+        Ok(Some(Self {
+            resampler,
+            sample_rate,
+            out_sample_rate,
+            channels,
+            pending: vec![Vec::with_capacity(BLOCK_FRAMES * 2); channels],
+            delay_frames,
+            input_frames: 0,
+            output_frames: 0,
+        }))
+    }
+
+    /// Returns interleaved output, possibly empty while the first block is being filled.
+    fn push(&mut self, pcm: &[f32]) -> ResampleResult<Vec<f32>> {
+        // deinterleave
+        pcm.chunks_exact(self.channels).for_each(|frame| {
+            frame
+                .iter()
+                .zip(self.pending.iter_mut())
+                .for_each(|(&sample, channel)| {
+                    channel.push(sample);
+                });
+        });
+        self.input_frames += pcm.len() / self.channels;
+
+        let mut output = Vec::new();
+
+        while self.pending[0].len() >= self.resampler.input_frames_next() {
+            let frames = self.resampler.input_frames_next();
+            let resampled = self.resampler.process(&self.pending, None)?;
+            self.pending.iter_mut().for_each(|channel| {
+                channel.drain(..frames);
+            });
+            self.append_output(resampled, usize::MAX, &mut output);
+        }
+
+        Ok(output)
+    }
+
+    /// Resamples the remaining input and pushes out the delayed frames,
+    /// so the output is exactly as long as the input in time.
+    fn flush(&mut self) -> ResampleResult<Vec<f32>> {
+        let expected_output_frames =
+            (self.input_frames * self.out_sample_rate).div_ceil(self.sample_rate);
+
+        let mut output = Vec::new();
+
+        if !self.pending[0].is_empty() {
+            let resampled = self.resampler.process_partial(Some(&self.pending), None)?;
+            self.pending.iter_mut().for_each(|channel| channel.clear());
+            self.append_output(resampled, expected_output_frames, &mut output);
+        }
+
+        while self.output_frames < expected_output_frames {
+            let resampled = self.resampler.process_partial(None, None)?;
+            self.append_output(resampled, expected_output_frames, &mut output);
+        }
+
+        Ok(output)
+    }
+
+    fn append_output(
+        &mut self,
+        resampled: Vec<Vec<f32>>,
+        max_output_frames: usize,
+        output: &mut Vec<f32>,
+    ) {
+        let frames = resampled[0].len();
+        let skip = self.delay_frames.min(frames);
+        self.delay_frames -= skip;
+
+        let take = (frames - skip).min(max_output_frames.saturating_sub(self.output_frames));
+        self.output_frames += take;
+
+        // interleave
+        output.extend(
+            (skip..skip + take).flat_map(|i| resampled.iter().map(move |channel| channel[i])),
+        );
+    }
+}
+
+fn sinc_interpolation_parameters(parameters: SincParameters) -> SincInterpolationParameters {
+    SincInterpolationParameters {
+        sinc_len: parameters.sinc_len,
+        f_cutoff: parameters.f_cutoff,
+        oversampling_factor: parameters.oversampling_factor,
+        interpolation: SincInterpolationType::Cubic,
+        window: WindowFunction::BlackmanHarris2,
+    }
+}
+
+/// Mono is duplicated to every channel, and everything is averaged down to mono.
+fn adapt_channels(chunk: DecodedChunk, channels: usize) -> Result<DecodedChunk, crate::Error> {
+    let pcm = if chunk.channels == 1 {
+        chunk
+            .pcm
+            .iter()
+            .flat_map(|&sample| std::iter::repeat_n(sample, channels))
+            .collect()
+    } else if channels == 1 {
+        chunk
+            .pcm
+            .chunks_exact(chunk.channels)
+            .map(|frame| frame.iter().sum::<f32>() / chunk.channels as f32)
+            .collect()
+    } else {
+        return Err(crate::Error::ChannelConversion {
+            reason: "channel count changed mid-stream",
+        });
+    };
+
+    Ok(DecodedChunk {
+        pcm,
+        channels,
+        sample_rate: chunk.sample_rate,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const PRESETS: [ResampleQuality; 4] = [
+        ResampleQuality::Fast,
+        ResampleQuality::Balanced,
+        ResampleQuality::Best,
+        ResampleQuality::Sinc(SincParameters {
+            sinc_len: 64,
+            f_cutoff: 0.9,
+            oversampling_factor: 128,
+        }),
+    ];
+
+    /// Mono, in odd sized pieces to exercise the re-blocking.
+    fn resample_mono(
+        pcm: &[f32],
+        sample_rate: usize,
+        out_sample_rate: usize,
+        quality: ResampleQuality,
+    ) -> Vec<f32> {
+        let mut resampler = BlockResampler::new_if_needed(sample_rate, out_sample_rate, 1, quality)
+            .unwrap()
+            .unwrap();
+        let mut output = Vec::new();
+        for piece in pcm.chunks(777) {
+            output.extend(resampler.push(piece).unwrap());
+        }
+        output.extend(resampler.flush().unwrap());
+        output
+    }
+
+    fn sine(frequency: f64, sample_rate: usize, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (0.5 * (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin()) as f32)
+            .collect()
+    }
+
+    /// Gain of `frequency` in dB relative to the 0.5 amplitude of [`sine`],
+    /// over the middle half to stay clear of the edges.
+    fn gain_db(pcm: &[f32], frequency: f64, sample_rate: usize) -> f64 {
+        let range = pcm.len() / 4..pcm.len() * 3 / 4;
+        let (sin, cos) = range.clone().fold((0.0, 0.0), |(sin, cos), i| {
+            let phase = 2.0 * PI * frequency * i as f64 / sample_rate as f64;
+            let sample = pcm[i] as f64;
+            (sin + sample * phase.sin(), cos + sample * phase.cos())
+        });
+        let amplitude = 2.0 * (sin * sin + cos * cos).sqrt() / range.len() as f64;
+        20.0 * (amplitude / 0.5).log10()
+    }
+
+    #[test]
+    fn passband_ripple() {
+        // Linear interpolation rolls off early
+        let passbands = [5000.0, 18000.0, 18000.0, 15000.0];
+
+        for (quality, passband) in PRESETS.into_iter().zip(passbands) {
+            let gains = [100.0, 1000.0, 5000.0, 10000.0, 15000.0, 18000.0]
+                .into_iter()
+                .filter(|&frequency| frequency <= passband)
+                .map(|frequency| {
+                    let output =
+                        resample_mono(&sine(frequency, 44100, 44100), 44100, 48000, quality);
+                    gain_db(&output, frequency, 48000)
+                })
+                .collect::<Vec<_>>();
+            let ripple = gains.iter().fold(f64::MIN, |a, &b| a.max(b))
+                - gains.iter().fold(f64::MAX, |a, &b| a.min(b));
+
+            let max_ripple = match quality {
+                ResampleQuality::Fast => 0.5,
+                _ => 0.1,
+            };
+            assert!(ripple < max_ripple, "{quality:?}: {ripple} dB ripple");
+        }
+    }
+
+    #[test]
+    fn aliasing() {
+        // Fast has no anti-aliasing filter
+        for quality in PRESETS.into_iter().skip(1) {
+            for frequency in [30000.0, 40000.0] {
+                let output = resample_mono(&sine(frequency, 96000, 96000), 96000, 48000, quality);
+                let rms = (output.iter().map(|&s| (s as f64).powi(2)).sum::<f64>()
+                    / output.len() as f64)
+                    .sqrt();
+                let alias_db = 20.0 * (rms * 2f64.sqrt() / 0.5).log10();
+                assert!(
+                    alias_db < -50.0,
+                    "{quality:?} at {frequency} Hz: {alias_db} dB"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn start_alignment() {
+        for quality in PRESETS {
+            for sample_rate in [22050, 32000, 44100, 96000] {
+                // 20 ms and a third of a second in, whole frames at 48 kHz
+                for pulse in [sample_rate / 50, sample_rate / 3] {
+                    let mut pcm = vec![0.0; sample_rate];
+                    pcm[pulse] = 1.0;
+
+                    let output = resample_mono(&pcm, sample_rate, 48000, quality);
+                    assert_eq!(output.len(), 48000);
+
+                    let peak = (0..output.len())
+                        .max_by(|&a, &b| output[a].abs().total_cmp(&output[b].abs()))
+                        .unwrap();
+                    let expected = pulse * 48000 / sample_rate;
+                    assert!(
+                        peak.abs_diff(expected) <= 1,
+                        "{quality:?} at {sample_rate} Hz: pulse at {peak} instead of {expected}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn chunk(frame: &[f32], frames: usize, sample_rate: usize) -> DecodedChunk {
+        DecodedChunk {
+            pcm: frame.repeat(frames),
+            channels: frame.len(),
+            sample_rate,
+        }
+    }
+
+    /// Through the whole stage, with the error it reported if any.
+    fn run(chunks: Vec<DecodedChunk>) -> (Vec<DecodedChunk>, Option<crate::Error>) {
+        let (in_tx, in_rx) = mpsc::channel();
+        for chunk in chunks {
+            in_tx.send(chunk).unwrap();
+        }
+        drop(in_tx);
+
+        let (err_tx, err_rx) = mpsc::channel();
+        let output = resample(in_rx, err_tx, ResampleQuality::Balanced)
+            .iter()
+            .collect();
+        (output, err_rx.try_recv().ok())
+    }
+
+    fn interleaved(chunks: &[DecodedChunk]) -> Vec<f32> {
+        chunks.iter().flat_map(|chunk| chunk.pcm.clone()).collect()
+    }
+
+    #[test]
+    fn mid_stream_changes() {
+        // Passed through, resampled, passed through and resampled again
+        let (output, error) = run(vec![
+            chunk(&[0.2, 0.6], 4800, 48000),
+            chunk(&[0.3], 4410, 44100),
+            chunk(&[0.2, 0.6], 4800, 48000),
+            chunk(&[0.2, 0.6], 2000, 44100),
+            chunk(&[0.2, 0.6], 2410, 44100),
+        ]);
+        assert!(error.is_none());
+        assert!(output
+            .iter()
+            .all(|chunk| chunk.channels == 2 && chunk.sample_rate == 48000));
+
+        let pcm = interleaved(&output);
+        assert_eq!(pcm.len(), 4800 * 4 * 2);
+        assert!(pcm[..4800 * 2] == [0.2, 0.6].repeat(4800));
+        // Mono duplicated to both channels
+        assert!(pcm[4800 * 2..4800 * 4]
+            .chunks_exact(2)
+            .all(|frame| frame[0] == frame[1]));
+        assert!(pcm[4800 * 4..4800 * 6] == [0.2, 0.6].repeat(4800));
+
+        let (output, error) = run(vec![
+            chunk(&[0.3], 4800, 48000),
+            chunk(&[0.2, 0.6], 4800, 48000),
+            chunk(&[0.2, 0.6], 1600, 16000),
+        ]);
+        assert!(error.is_none());
+        assert!(output
+            .iter()
+            .all(|chunk| chunk.channels == 1 && chunk.sample_rate == 48000));
+
+        let pcm = interleaved(&output);
+        assert_eq!(pcm.len(), 4800 * 3);
+        // Stereo averaged down to mono
+        assert!(pcm[4800..4800 * 2]
+            .iter()
+            .all(|sample| (sample - 0.4).abs() < 1e-6));
+    }
+
+    #[test]
+    fn unsupported_channel_change() {
+        let (output, error) = run(vec![
+            chunk(&[0.2, 0.6], 4800, 48000),
+            chunk(&[0.1; 6], 4800, 48000),
+            chunk(&[0.2, 0.6], 4800, 48000),
+        ]);
+
+        assert!(matches!(
+            error,
+            Some(crate::Error::ChannelConversion { .. })
+        ));
+        assert_eq!(interleaved(&output).len(), 4800 * 2);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{
+    decoded_chunk::{self, DecodedChunk},
+    ConversionStats, SilenceTrim,
+};
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+/// Drops leading and trailing runs of frames where every channel stays below the threshold,
+/// if the run lasts at least the minimum duration.
+pub fn trim_silence(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+    silence_trim: SilenceTrim,
+    stats: Arc<Mutex<ConversionStats>>,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let now = std::time::Instant::now();
+        let threshold = 10f32.powf(silence_trim.threshold_dbfs / 20.0);
+        let min_duration = silence_trim.min_duration.as_secs_f64();
+
+        let mut is_leading = true;
+        // Silence after the last non-silent frame, held back until we know if it's trailing
+        let mut silent_run: Vec<DecodedChunk> = Vec::new();
+        let mut silent_run_duration = 0f64;
+        let mut trimmed_leading = 0f64;
+        let mut trimmed_trailing = 0f64;
+
+        let result: anyhow::Result<()> = (|| {
+            while let Ok(chunk) = in_rx.recv() {
+                let channels = chunk.channels;
+                let is_sound = |frame: &[f32]| frame.iter().any(|sample| sample.abs() >= threshold);
+                // Silence between sounds stays in the chunk, only its ends matter
+                let first_sound = chunk.pcm.chunks_exact(channels).position(is_sound);
+                let last_sound = chunk.pcm.chunks_exact(channels).rposition(is_sound);
+                let (Some(first_sound), Some(last_sound)) = (first_sound, last_sound) else {
+                    silent_run_duration += duration(chunk.pcm.len() / channels, chunk.sample_rate);
+                    silent_run.push(chunk);
+                    continue;
+                };
+
+                let mut start = 0;
+                if is_leading {
+                    is_leading = false;
+                    let leading = silent_run_duration + duration(first_sound, chunk.sample_rate);
+                    if leading >= min_duration {
+                        trimmed_leading = leading;
+                        silent_run.clear();
+                        start = first_sound;
+                    }
+                }
+
+                let end = (last_sound + 1) * channels;
+                let tail = DecodedChunk {
+                    pcm: chunk.pcm[end..].to_vec(),
+                    channels,
+                    sample_rate: chunk.sample_rate,
+                };
+                let mut sound = chunk;
+                sound.pcm.truncate(end);
+                sound.pcm.drain(..start * channels);
+
+                send_after_run(&out_tx, &mut silent_run, sound)?;
+                silent_run_duration = duration(tail.pcm.len() / channels, tail.sample_rate);
+                if !tail.pcm.is_empty() {
+                    silent_run.push(tail);
+                }
+            }
+
+            if silent_run_duration >= min_duration {
+                if is_leading {
+                    trimmed_leading = silent_run_duration;
+                } else {
+                    trimmed_trailing = silent_run_duration;
+                }
+                silent_run.clear();
+            }
+            for silent_chunk in silent_run.drain(..) {
+                out_tx.send(silent_chunk)?;
+            }
+
+            Ok(())
+        })();
+
+        println!(
+            "silence trim thread finished, trimmed {:.3}s leading and {:.3}s trailing, elapsed: {:?}",
+            trimmed_leading,
+            trimmed_trailing,
+            now.elapsed()
+        );
+
+        decoded_chunk::close_with(out_tx, &stats, |stats| {
+            if result.is_ok() {
+                stats.trimmed_leading_silence = Duration::from_secs_f64(trimmed_leading);
+                stats.trimmed_trailing_silence = Duration::from_secs_f64(trimmed_trailing);
+            }
+        });
+    });
+
+    out_rx
+}
+
+fn duration(frames: usize, sample_rate: usize) -> f64 {
+    frames as f64 / sample_rate as f64
+}
+
+/// Sends the held back silence and `sound`, in one chunk if they have the same format.
+fn send_after_run(
+    out_tx: &mpsc::Sender<DecodedChunk>,
+    silent_run: &mut Vec<DecodedChunk>,
+    sound: DecodedChunk,
+) -> anyhow::Result<()> {
+    let same_format = silent_run.iter().all(|silent_chunk| {
+        silent_chunk.channels == sound.channels && silent_chunk.sample_rate == sound.sample_rate
+    });
+    if !same_format {
+        for silent_chunk in silent_run.drain(..) {
+            out_tx.send(silent_chunk)?;
+        }
+        out_tx.send(sound)?;
+        return Ok(());
+    }
+
+    let mut pcm = Vec::with_capacity(
+        silent_run
+            .iter()
+            .map(|chunk| chunk.pcm.len())
+            .sum::<usize>()
+            + sound.pcm.len(),
+    );
+    for silent_chunk in silent_run.drain(..) {
+        pcm.extend(silent_chunk.pcm);
+    }
+    pcm.extend(sound.pcm);
+    out_tx.send(DecodedChunk { pcm, ..sound })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mono at 48 kHz, in chunks of `chunk_frames`.
+    fn run(pcm: &[f32], chunk_frames: usize) -> (Vec<DecodedChunk>, ConversionStats) {
+        let (in_tx, in_rx) = mpsc::channel();
+        for chunk in pcm.chunks(chunk_frames) {
+            in_tx
+                .send(DecodedChunk {
+                    pcm: chunk.to_vec(),
+                    channels: 1,
+                    sample_rate: 48000,
+                })
+                .unwrap();
+        }
+        drop(in_tx);
+
+        let stats = Arc::new(Mutex::new(ConversionStats::default()));
+        let silence_trim = SilenceTrim {
+            threshold_dbfs: -60.0,
+            min_duration: Duration::from_millis(100),
+        };
+        let output = trim_silence(in_rx, silence_trim, stats.clone())
+            .iter()
+            .collect();
+        let stats = stats.lock().unwrap().clone();
+        (output, stats)
+    }
+
+    fn tone(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 100.0 * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn zero_crossings_stay_in_their_chunk() {
+        let pcm = tone(48000);
+        let (output, _) = run(&pcm, 1152);
+
+        assert!(output.len() <= pcm.len().div_ceil(1152));
+        let output_pcm = output
+            .into_iter()
+            .flat_map(|chunk| chunk.pcm)
+            .collect::<Vec<_>>();
+        assert!(output_pcm == pcm);
+    }
+
+    #[test]
+    fn leading_and_trailing_silence() {
+        // A short gap in the middle is kept
+        let sound = [tone(24000), vec![0.0; 2400], tone(24000)].concat();
+        let pcm = [vec![0.0; 24000], sound.clone(), vec![0.0; 12000]].concat();
+        let (output, stats) = run(&pcm, 1000);
+
+        let output_pcm = output
+            .into_iter()
+            .flat_map(|chunk| chunk.pcm)
+            .collect::<Vec<_>>();
+        // The tone starts on a zero crossing, which is trimmed with the silence
+        assert!(output_pcm == sound[1..], "{} frames", output_pcm.len());
+        assert_eq!(stats.trimmed_leading_silence.as_micros(), 500_020);
+        assert_eq!(stats.trimmed_trailing_silence.as_micros(), 250_000);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::decoded_chunk::DecodedChunk;
+use std::{sync::mpsc, time::Duration};
+
+/// Drops the samples before `start`, then passes `duration` worth of samples
+/// and drops everything after it.
+pub fn trim(
+    in_rx: mpsc::Receiver<DecodedChunk>,
+    start: Duration,
+    duration: Option<Duration>,
+) -> mpsc::Receiver<DecodedChunk> {
+    let (out_tx, out_rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let now = std::time::Instant::now();
+        let start = start.as_secs_f64();
+        let end = duration.map_or(f64::INFINITY, |duration| start + duration.as_secs_f64());
+        // In seconds, because the sample rate can change between chunks
+        let mut position = 0f64;
+
+        while let Ok(mut chunk) = in_rx.recv() {
+            let frames = chunk.pcm.len() / chunk.channels;
+            let to_frames = |time: f64| {
+                ((time - position) * chunk.sample_rate as f64)
+                    .round()
+                    .max(0.0) as usize
+            };
+            let skip_frames = to_frames(start).min(frames);
+            let remaining_frames = to_frames(end);
+            position += frames as f64 / chunk.sample_rate as f64;
+
+            let is_end = remaining_frames <= frames;
+            if is_end {
+                chunk.pcm.truncate(remaining_frames * chunk.channels);
+            }
+            chunk
+                .pcm
+                .drain(..(skip_frames * chunk.channels).min(chunk.pcm.len()));
+
+            if !chunk.pcm.is_empty() && out_tx.send(chunk).is_err() {
+                break;
+            }
+
+            if is_end {
+                // Dropping in_rx stops the decoder too
+                break;
+            }
+        }
+
+        println!("trim thread finished, elapsed: {:?}", now.elapsed());
+    });
+
+    out_rx
+}